@@ -0,0 +1,123 @@
+//! Statistics to decide which tracks to play, and how, before touching the hardware
+
+use std::cmp;
+use std::time::Duration;
+
+use song::{Event, Track};
+use util;
+
+/// Statistics of a song, per track and for all tracks played together
+#[derive(Clone, Debug)]
+pub struct SongAnalysis {
+    /// Statistics of each track, in the same order as `Song::tracks`
+    pub tracks: Vec<TrackStats>,
+    /// Statistics of all tracks merged together
+    pub merged: TrackStats
+}
+
+/// Statistics of a single track
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TrackStats {
+    /// Maximum amount of notes played at the same time, which is the amount of buzzers
+    /// needed to play the track without dropping notes
+    pub max_polyphony: usize,
+    /// Lowest and highest note played, if any
+    pub range: Option<(u8, u8)>,
+    /// Amount of notes outside of the C1-B7 range supported by the buzzers
+    pub out_of_range: usize,
+    /// Amount of notes played
+    pub notes: usize,
+    /// Time until the last event of the track
+    pub duration: Duration
+}
+
+impl TrackStats {
+    /// Compute the statistics of the given track
+    pub fn new(track: &Track) -> TrackStats {
+        let mut stats = TrackStats::default();
+        let mut playing = Vec::new();
+        let mut micros = 0;
+        for &event in track.events() {
+            match event {
+                Event::Play { tone, channel, track, .. } => {
+                    // A re-trigger of a note that is still playing reuses its buzzer
+                    if !playing.contains(&(tone, channel, track)) {
+                        playing.push((tone, channel, track));
+                    }
+
+                    stats.max_polyphony = cmp::max(stats.max_polyphony, playing.len());
+                    stats.notes += 1;
+
+                    stats.range = Some(match stats.range {
+                        Some((low, high)) => (cmp::min(low, tone), cmp::max(high, tone)),
+                        None => (tone, tone)
+                    });
+
+                    if util::midi_code_to_freq(tone).is_none() {
+                        stats.out_of_range += 1;
+                    }
+                }
+                Event::Stop { tone, channel, track } => {
+                    if let Some(pos) = playing.iter().position(|&n| n == (tone, channel, track)) {
+                        playing.swap_remove(pos);
+                    }
+                }
+                Event::Wait(time) => micros += time as u64
+            }
+        }
+
+        stats.duration = Duration::from_micros(micros);
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::TrackStats;
+    use song::{Event, Track};
+
+    fn play(tone: u8, track: usize) -> Event {
+        Event::Play { tone, velocity: 100, channel: 0, track }
+    }
+
+    fn stop(tone: u8, track: usize) -> Event {
+        Event::Stop { tone, channel: 0, track }
+    }
+
+    #[test]
+    fn stats() {
+        let track = Track::new(vec![
+            play(60, 0), play(64, 0), Event::Wait(1000),
+            play(12, 0), Event::Wait(1000),
+            stop(60, 0), stop(64, 0), stop(12, 0), play(110, 0), Event::Wait(500),
+            stop(110, 0)
+        ]);
+
+        assert_eq!(TrackStats::new(&track), TrackStats {
+            max_polyphony: 3,
+            range: Some((12, 110)),
+            out_of_range: 2,
+            notes: 4,
+            duration: Duration::from_micros(2500)
+        });
+    }
+
+    #[test]
+    fn retrigger() {
+        // A re-triggered note keeps its buzzer, but the same note of another track needs its own
+        let track = Track::new(vec![play(60, 0), Event::Wait(1000), play(60, 0), play(60, 1), stop(60, 0), stop(60, 1)]);
+        let stats = TrackStats::new(&track);
+        assert_eq!(stats.max_polyphony, 2);
+        assert_eq!(stats.notes, 3);
+    }
+
+    #[test]
+    fn empty() {
+        assert_eq!(TrackStats::new(&Track::new(vec![Event::Wait(1000)])), TrackStats {
+            duration: Duration::from_micros(1000),
+            ..TrackStats::default()
+        });
+    }
+}
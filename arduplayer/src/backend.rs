@@ -0,0 +1,38 @@
+//! Output devices that can play the notes chosen by the player
+
+use std::io;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Something that can make buzzers sound, like an Arduino connected through the serial port
+pub trait Backend {
+    /// Make the buzzer play the given frequency, in Hz, or stop it if the frequency is 0
+    fn set_frequency(&mut self, buzzer: u8, freq: u16) -> io::Result<()>;
+
+    /// Stop all buzzers
+    fn silence_all(&mut self) -> io::Result<()>;
+
+    /// Make sure all changes so far have reached the device
+    fn flush(&mut self) -> io::Result<()>;
+
+    /// Let the buzzers sound for the given time
+    ///
+    /// Backends that don't play in real time override this and `now` to advance their
+    /// own clock
+    fn wait(&mut self, duration: Duration) {
+        thread::sleep(duration);
+    }
+
+    /// Return the current instant of the clock used to schedule the notes
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    /// Return whether the clock of this backend follows the wall clock
+    ///
+    /// Backends with their own clock return false, so the player doesn't spend real time
+    /// waiting for controls between notes
+    fn is_realtime(&self) -> bool {
+        true
+    }
+}
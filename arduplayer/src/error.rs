@@ -0,0 +1,51 @@
+//! Errors that can occur while loading a song
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// An error found while loading a MIDI file
+#[derive(Debug)]
+pub enum SongError {
+    /// The file could not be read
+    Io(io::Error),
+    /// The header chunk is missing or contains invalid data
+    MalformedHeader(String),
+    /// The file ended in the middle of the given track
+    TruncatedTrack { track: usize },
+    /// The given track contains data that is not valid MIDI
+    MalformedTrack { track: usize, reason: String },
+    /// Data that is not a chunk, such as MIDI events, was found before the first track chunk
+    EventBeforeTrack,
+    /// Data that is not a chunk was found after the given track, so the rest of the file
+    /// can't be read
+    InvalidChunk { after_track: usize, tag: [u8; 4] }
+}
+
+impl fmt::Display for SongError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SongError::Io(err) => write!(f, "I/O error: {}", err),
+            SongError::MalformedHeader(reason) => write!(f, "malformed header: {}", reason),
+            SongError::TruncatedTrack { track } => write!(f, "track {} is truncated", track),
+            SongError::MalformedTrack { track, reason } => write!(f, "malformed track {}: {}", track, reason),
+            SongError::EventBeforeTrack => write!(f, "event found before the first track"),
+            SongError::InvalidChunk { after_track, tag } => write!(f, "invalid chunk tag {:?} after track {}", tag, after_track)
+        }
+    }
+}
+
+impl Error for SongError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SongError::Io(err) => Some(err),
+            _ => None
+        }
+    }
+}
+
+impl From<io::Error> for SongError {
+    fn from(err: io::Error) -> SongError {
+        SongError::Io(err)
+    }
+}
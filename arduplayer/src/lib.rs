@@ -5,14 +5,38 @@
 
 extern crate byteorder;
 extern crate ghakuf;
+extern crate rand;
 extern crate serialport;
 
+mod analysis;
+mod backend;
+mod error;
 mod serial;
 mod midi_parser;
+mod midi_writer;
 mod note_scheduler;
+mod playback;
 mod player;
+mod playlist;
+mod recording;
+mod selection;
 mod song;
+mod tempo;
 mod util;
+mod voice;
+mod wav;
 
-pub use player::{Player, PlayerOptions};
-pub use song::{Event, Song, Track};
+pub use analysis::{SongAnalysis, TrackStats};
+pub use backend::Backend;
+pub use error::SongError;
+pub use midi_writer::MidiFormat;
+pub use playback::{PlaybackHandle, PlaybackReport};
+pub use player::{Arpeggio, Loop, Player, PlayerOptions, Position};
+pub use playlist::{Playlist, PlaylistHandle, Repeat};
+pub use recording::{Command, RecordingBackend};
+pub use serial::SerialBackend;
+pub use song::{Event, LoadOptions, Song, Track};
+pub use tempo::{TempoChange, TempoMap, TimeBase, TimeSignature};
+pub use util::OutOfRange;
+pub use voice::{Voice, VoiceAllocator, VoicePolicy};
+pub use wav::WavBackend;
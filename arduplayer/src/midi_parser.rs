@@ -1,14 +1,27 @@
-use std::path::Path;
+use std::cmp;
+use std::io::{self, Read};
 
-use ghakuf::reader::{Handler, Reader};
-use ghakuf::messages::{MetaEvent, MidiEvent, SysExEvent};
+use byteorder::{BigEndian, ByteOrder};
+use ghakuf::reader::Handler;
+use ghakuf::messages::{MetaEvent, MidiEvent, MidiEventBuilder, SysExEvent};
 
-use song::{Song, Track, Event};
+use error::SongError;
+use song::{LoadOptions, Song, Track, Event};
+use tempo::{TempoChange, TempoMap, TimeBase, TimeSignature};
+
+/// Reason used when an event does not fit in the data of its track chunk
+const END_OF_TRACK: &str = "event extends past the end of the track";
 
 /// A parser to extract NoteOn and NoteOff MIDI events, per track
 pub struct MidiParser {
-    time_base: Option<u16>,
+    time_base: Option<TimeBase>,
     tracks: Vec<MidiTrack>,
+    /// Set Tempo events of all tracks, which in format 1 files apply to the whole song
+    tempo_changes: Vec<TempoChange>,
+    /// Time Signature events of all tracks
+    time_signatures: Vec<TimeSignature>,
+    /// Recoverable problems found while parsing
+    warnings: Vec<SongError>
 }
 
 /// Represents a track that is being parsed
@@ -16,102 +29,316 @@ pub struct MidiParser {
 /// Note that, after parsing, the events will be exported to a `song::Track`
 pub struct MidiTrack {
     name: Option<String>,
-    notes: Vec<Event>,
+    /// Pairs of channel and General MIDI program, from `ProgramChange` events
+    programs: Vec<(u8, u8)>,
+    /// Notes, along with their absolute position in ticks
+    notes: Vec<(u32, Event)>,
     unknown_events: u32,
-    ignored_dt: u32
+    /// Absolute position of the last parsed event, in ticks
+    time: u32
 }
 
 impl MidiTrack {
-    fn next_wait(&mut self) -> u32 {
-        let dt = self.ignored_dt;
-        self.ignored_dt = 0;
-        dt
+    /// Split this track into one track per MIDI channel used by its notes
+    ///
+    /// Tracks with notes on a single channel (or without notes) are returned unchanged
+    fn split_channels(self) -> Vec<MidiTrack> {
+        let mut channels: Vec<_> = self.notes.iter().filter_map(|&(_, e)| e.channel()).collect();
+        channels.sort();
+        channels.dedup();
+
+        if channels.len() <= 1 {
+            return vec![self];
+        }
+
+        let MidiTrack { name, programs, notes, unknown_events, time } = self;
+        channels.into_iter().enumerate().map(|(i, channel)| MidiTrack {
+            name: name.clone(),
+            programs: programs.iter().cloned().filter(|&(ch, _)| ch == channel).collect(),
+            notes: notes.iter().cloned().filter(|&(_, e)| e.channel() == Some(channel)).collect(),
+            // There is no way to know which channel the unknown events belonged to
+            unknown_events: if i == 0 { unknown_events } else { 0 },
+            time
+        }).collect()
+    }
+
+    /// Convert the notes to a `song::Track`, using real time for the waits between them
+    ///
+    /// `index` is the position of the track in the song, which is stored in its events
+    fn into_track(self, index: usize, tempo_map: &TempoMap, time_base: TimeBase) -> Track {
+        let mut events = Vec::with_capacity(self.notes.len() * 2);
+        let mut last_micros = 0;
+        for (tick, mut event) in self.notes {
+            event.set_track(index);
+
+            // Waits that don't fit in an `Event::Wait` (about 71 minutes) are split
+            let micros = tempo_map.ticks_to_micros(tick, time_base);
+            while micros > last_micros {
+                let wait = cmp::min(micros - last_micros, u32::MAX as u64);
+                events.push(Event::Wait(wait as u32));
+                last_micros += wait;
+            }
+
+            events.push(event);
+        }
+
+        let mut track = Track::new(events);
+        track.name = self.name;
+        track.program = self.programs.first().map(|&(_, program)| program);
+        track.unknown_events = self.unknown_events;
+        track
     }
 }
 
 impl MidiParser {
     fn new() -> MidiParser {
-        MidiParser { time_base: None, tracks: Vec::new() }
-    }
-
-    fn current_track_opt(&mut self) -> Option<&mut MidiTrack> {
-        let i = self.tracks.len() - 1;
-        self.tracks.get_mut(i)
+        MidiParser { time_base: None, tracks: Vec::new(), tempo_changes: Vec::new(), time_signatures: Vec::new(), warnings: Vec::new() }
     }
 
     fn current_track(&mut self) -> &mut MidiTrack {
-        self.current_track_opt().unwrap()
+        self.tracks.last_mut().unwrap()
     }
 
     fn add_track(&mut self) {
         let name = None;
+        let programs = Vec::new();
         let notes = Vec::new();
         let unknown_events = 0;
-        let ignored_dt = 0;
-        self.tracks.push(MidiTrack { name, notes, unknown_events, ignored_dt });
+        let time = 0;
+        self.tracks.push(MidiTrack { name, programs, notes, unknown_events, time });
+    }
+
+    /// Advance the current track by `delta_time` ticks and return the new position
+    fn advance(&mut self, delta_time: u32) -> u32 {
+        let track = self.current_track();
+        track.time += delta_time;
+        track.time
     }
 
-    /// Load a song located at the given path
+    /// Add a note to the current track, `delta_time` ticks after the previous event
+    fn push_note(&mut self, delta_time: u32, event: Event) {
+        let tick = self.advance(delta_time);
+        self.current_track().notes.push((tick, event));
+    }
+
+    /// Load a song from the contents of a MIDI file
     ///
-    /// Note: panics if the file does not exist. Other errors are logged to stderr
-    /// and ignored afterwards
-    pub fn load_song(path: &Path) -> Song {
+    /// Unless `options.lenient` is set, any problem found in the file is returned as an error.
+    /// Otherwise, recoverable problems are collected in `Song::warnings`
+    pub fn load_song<R: Read>(mut source: R, options: &LoadOptions) -> Result<Song, SongError> {
         let mut handler = MidiParser::new();
-        let mut reader = Reader::new(&mut handler, path).unwrap();
-        if let Some(err) = reader.read().err() {
-            eprintln!("Error reading midi file: {}", err)
+        handler.read(&mut source)?;
+
+        if !options.lenient && !handler.warnings.is_empty() {
+            return Err(handler.warnings.swap_remove(0));
+        }
+
+        let time_base = handler.time_base.ok_or_else(|| SongError::MalformedHeader("missing header".into()))?;
+        let tempo_map = TempoMap::new(handler.tempo_changes);
+        let tracks = if options.split_channels {
+            handler.tracks.into_iter().flat_map(MidiTrack::split_channels).collect()
+        } else {
+            handler.tracks
+        };
+
+        let tracks = tracks.into_iter().enumerate().map(|(i, t)| t.into_track(i, &tempo_map, time_base)).collect();
+        let warnings = handler.warnings;
+
+        let mut time_signatures = handler.time_signatures;
+        time_signatures.sort_by_key(|s| s.tick);
+
+        Ok(Song { time_base, tempo_map, time_signatures, tracks, warnings })
+    }
+
+    /// Read a Standard MIDI File, feeding its contents to our `Handler` implementation
+    ///
+    /// Only problems that make it impossible to interpret the rest of the file are returned
+    /// as errors. Other problems are recorded in `self.warnings`
+    fn read<R: Read>(&mut self, source: &mut R) -> Result<(), SongError> {
+        let mut header = [0; 14];
+        if read_full(source, &mut header)? < header.len() {
+            return Err(SongError::MalformedHeader("file is too short".into()));
+        }
+
+        if &header[0..4] != b"MThd" {
+            return Err(SongError::MalformedHeader("missing MThd chunk".into()));
+        }
+
+        let length = BigEndian::read_u32(&header[4..8]);
+        if length < 6 {
+            return Err(SongError::MalformedHeader(format!("header chunk is too short ({} bytes)", length)));
+        }
+
+        // Newer versions of the format may add fields to the header, which we skip
+        io::copy(&mut source.by_ref().take(length as u64 - 6), &mut io::sink())?;
+
+        let format = BigEndian::read_u16(&header[8..10]);
+        let track_count = BigEndian::read_u16(&header[10..12]);
+        let division = BigEndian::read_u16(&header[12..14]);
+        if TimeBase::from_division(division).is_none() {
+            return Err(SongError::MalformedHeader(format!("invalid time division {:#06x}", division)));
+        }
+
+        self.header(format, track_count, division);
+
+        // Anything after the last track, such as padding, is ignored
+        while self.tracks.len() < track_count as usize {
+            let mut chunk = [0; 8];
+            let read = read_full(source, &mut chunk)?;
+            if read == 0 {
+                return Ok(());
+            }
+
+            if read < chunk.len() {
+                self.warnings.push(SongError::TruncatedTrack { track: self.tracks.len() });
+                return Ok(());
+            }
+
+            let tag = &chunk[0..4];
+            let length = BigEndian::read_u32(&chunk[4..8]);
+            if tag == b"MTrk" {
+                let mut data = Vec::new();
+                source.by_ref().take(length as u64).read_to_end(&mut data)?;
+                let truncated = data.len() < length as usize;
+
+                self.track_change();
+                let track = self.tracks.len() - 1;
+                let result = self.read_track(&data);
+
+                // Running out of data is expected if the chunk itself was cut short
+                if truncated {
+                    self.warnings.push(SongError::TruncatedTrack { track });
+                    return Ok(());
+                }
+
+                if let Err(reason) = result {
+                    self.warnings.push(SongError::MalformedTrack { track, reason });
+                }
+            } else if tag.iter().all(u8::is_ascii_alphanumeric) {
+                // The spec says that unknown chunk types should be ignored
+                io::copy(&mut source.by_ref().take(length as u64), &mut io::sink())?;
+            } else {
+                // This is not a chunk, so we have no way to find where the next track starts
+                let err = if self.tracks.is_empty() {
+                    SongError::EventBeforeTrack
+                } else {
+                    SongError::InvalidChunk { after_track: self.tracks.len() - 1, tag: [tag[0], tag[1], tag[2], tag[3]] }
+                };
+
+                self.warnings.push(err);
+                return Ok(());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Read the events of a track chunk
+    fn read_track(&mut self, mut data: &[u8]) -> Result<(), String> {
+        let mut running_status = None;
+        while !data.is_empty() {
+            let delta_time = read_vlq(&mut data)?;
+            if self.current_track().time.checked_add(delta_time).is_none() {
+                return Err("event is too far from the start of the track".into());
+            }
+
+            // Status bytes have their top bit set. Otherwise the status of the
+            // previous MIDI event is reused ("running status")
+            let status = match data.first() {
+                Some(&byte) if byte & 0x80 != 0 => read_byte(&mut data)?,
+                _ => running_status.ok_or_else(|| String::from("data byte without running status"))?
+            };
+
+            match status {
+                0xff => {
+                    let event = MetaEvent::new(read_byte(&mut data)?);
+                    let len = read_vlq(&mut data)?;
+                    let bytes = read_bytes(&mut data, len)?;
+                    self.meta_event(delta_time, &event, &bytes.to_vec());
+                    running_status = None;
+
+                    if event == MetaEvent::EndOfTrack {
+                        return Ok(());
+                    }
+                }
+                0xf0 | 0xf7 => {
+                    let len = read_vlq(&mut data)?;
+                    let bytes = read_bytes(&mut data, len)?;
+                    self.sys_ex_event(delta_time, &SysExEvent::new(status), &bytes.to_vec());
+                    running_status = None;
+                }
+                0x80..=0xef => {
+                    let mut builder = MidiEventBuilder::new(status);
+                    while builder.shortage() > 0 {
+                        builder.push(read_byte(&mut data)?);
+                    }
+
+                    self.midi_event(delta_time, &builder.build());
+                    running_status = Some(status);
+                }
+                _ => return Err(format!("unknown status byte {:#04x}", status))
+            }
         }
 
-        // debug_tracks(&handler.tracks);
+        Ok(())
+    }
+}
 
-        Song {
-            time_base: handler.time_base.unwrap(),
-            tracks: handler.tracks.into_iter().map(|t| Track::new(t.notes)).collect()
+/// Fill `buf` with bytes from `source`, returning less than `buf.len()` only at the end of the input
+fn read_full<R: Read>(source: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match source.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+            Err(err) => return Err(err)
         }
     }
+
+    Ok(read)
 }
 
-// fn debug_tracks(tracks: &[MidiTrack]) {
-//     use note_scheduler::NoteScheduler;
-//     println!("Tracks:");
-//     for (index, t) in tracks.iter().enumerate() {
-//         // Play the thing virtually
-//         let mut player = NoteScheduler::new(50);
-//         for &event in &t.notes {
-//             match event {
-//                 Event::Play { tone, .. } => {
-//                     player.start_note(tone);
-
-//                 }
-//                 Event::Stop { tone } => {
-//                     player.stop_note(tone);
-
-//                 }
-//                 Event::Wait(_) => {
-//                     // Ignore wait events, since we are only interested in seeing how many
-//                     // notes are played in parallel
-//                 }
-//             }
-//         }
-
-//         println!("{}. {} (min buzzers: {})",
-//             index,
-//             t.name.as_ref().unwrap_or(&String::from("<unknown>")),
-//             player.playing.len()
-//         );
-//     }
-// }
+fn read_byte(data: &mut &[u8]) -> Result<u8, String> {
+    Ok(read_bytes(data, 1)?[0])
+}
+
+fn read_bytes<'a>(data: &mut &'a [u8], len: u32) -> Result<&'a [u8], String> {
+    let len = len as usize;
+    if data.len() < len {
+        return Err(END_OF_TRACK.into());
+    }
+
+    let (bytes, rest) = data.split_at(len);
+    *data = rest;
+    Ok(bytes)
+}
+
+/// Read a variable-length quantity, as used by delta times and lengths
+fn read_vlq(data: &mut &[u8]) -> Result<u32, String> {
+    let mut value = 0;
+    // A VLQ has at most 4 bytes, with the top bit set in all but the last one
+    for _ in 0..4 {
+        let byte = read_byte(data)?;
+        value = value << 7 | (byte & 0x7f) as u32;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+
+    Err("variable-length quantity is too long".into())
+}
 
 impl Handler for MidiParser {
     fn header(&mut self, _format: u16, _track: u16, time_base: u16) {
-        assert_eq!(self.time_base, None);
-        self.time_base = Some(time_base);
+        self.time_base = TimeBase::from_division(time_base);
     }
 
     fn meta_event(&mut self, delta_time: u32, event: &MetaEvent, data: &Vec<u8>) {
         match event {
             &MetaEvent::SequenceOrTrackName => {
-                assert_eq!(delta_time, 0);
+                // The name should come before any other event, but we don't rely on it
+                self.advance(delta_time);
 
                 let name = String::from_utf8_lossy(data);
                 self.current_track().name = Some(name.to_string());
@@ -122,55 +349,62 @@ impl Handler for MidiParser {
 
                 // Track ending, we can safely ignore it
             }
+            &MetaEvent::SetTempo => {
+                let tick = self.advance(delta_time);
+                let micros_per_quarter = data.iter().fold(0, |acc, &b| acc << 8 | b as u32);
+                if data.len() == 3 && micros_per_quarter > 0 {
+                    self.tempo_changes.push(TempoChange { tick, micros_per_quarter });
+                } else {
+                    let reason = format!("invalid tempo {:?}", data);
+                    self.warnings.push(SongError::MalformedTrack { track: self.tracks.len() - 1, reason });
+                }
+            }
+            &MetaEvent::TimeSignature => {
+                let tick = self.advance(delta_time);
+                // The denominator is stored as a power of two
+                if data.len() >= 2 && data[1] < 8 {
+                    self.time_signatures.push(TimeSignature { tick, numerator: data[0], denominator: 1 << data[1] });
+                }
+            }
             _ => {
-                self.current_track().ignored_dt += delta_time;
+                self.advance(delta_time);
                 // println!("Meta event: {} {}", delta_time, event);
             }
         }
     }
 
     fn midi_event(&mut self, delta_time: u32, event: &MidiEvent) {
-        // Note: we are ignoring channel information
-
         // Important: some files encode `NoteOff` as a `NoteOn` with velocity 0
         match *event {
-            MidiEvent::NoteOn { note, velocity: 0, .. } => {
-                let wait = Event::Wait(self.current_track().next_wait() + delta_time);
-                let stop = Event::Stop { tone: note };
-
-                self.current_track().notes.push(wait);
-                self.current_track().notes.push(stop);
+            MidiEvent::NoteOn { ch, note, velocity: 0 } => {
+                self.push_note(delta_time, Event::Stop { tone: note, channel: ch, track: 0 });
             }
-            MidiEvent::NoteOn { note, velocity, .. } => {
-                let wait = Event::Wait(self.current_track().next_wait() + delta_time);
-                let play = Event::Play { tone: note, velocity };
-
-                self.current_track().notes.push(wait);
-                self.current_track().notes.push(play);
+            MidiEvent::NoteOn { ch, note, velocity } => {
+                self.push_note(delta_time, Event::Play { tone: note, velocity, channel: ch, track: 0 });
             }
-            MidiEvent::NoteOff { note, .. } => {
-                let wait = Event::Wait(self.current_track().next_wait() + delta_time);
-                let stop = Event::Stop { tone: note };
-
-                self.current_track().notes.push(wait);
-                self.current_track().notes.push(stop);
+            MidiEvent::NoteOff { ch, note, .. } => {
+                self.push_note(delta_time, Event::Stop { tone: note, channel: ch, track: 0 });
             }
-            MidiEvent::ProgramChange { .. } => {
-                // Looks like the program change event is used to set the instrument
-                // Therefore we can ignore it
+            MidiEvent::ProgramChange { ch, program } => {
+                // The program change event is used to set the instrument. Buzzers
+                // can't change their sound, but the instrument tells us what the track is
+                self.advance(delta_time);
 
-                self.current_track().ignored_dt += delta_time;
+                let programs = &mut self.current_track().programs;
+                if !programs.iter().any(|&(channel, _)| channel == ch) {
+                    programs.push((ch, program));
+                }
             }
             MidiEvent::ControlChange { .. } => {
                 // Example control changes: vibrato, piano pedal, reverb, etc
                 // Since our little buzzers don't have anything like this, we can ignore it
 
-                self.current_track().ignored_dt += delta_time;
+                self.advance(delta_time);
             }
             _ => {
                 // println!("Unknown midi event: {}", event);
 
-                self.current_track().ignored_dt += delta_time;
+                self.advance(delta_time);
                 self.current_track().unknown_events += 1;
             }
         }
@@ -179,7 +413,7 @@ impl Handler for MidiParser {
     fn sys_ex_event(&mut self, delta_time: u32, _event: &SysExEvent, _data: &Vec<u8>) {
         // println!("Sys Ex event found, ignoring track: {} {}", delta_time, event);
 
-        self.current_track().ignored_dt += delta_time;
+        self.advance(delta_time);
         self.current_track().unknown_events += 1;
     }
 
@@ -188,3 +422,216 @@ impl Handler for MidiParser {
         self.add_track();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use error::SongError;
+    use song::{Event, LoadOptions, Song};
+    use tempo::TimeBase;
+
+    /// Build a MIDI file with the given division and track chunks
+    fn midi_file(division: u16, tracks: &[&[u8]]) -> Vec<u8> {
+        let mut bytes = b"MThd\0\0\0\x06\0\x01".to_vec();
+        bytes.extend_from_slice(&[0, tracks.len() as u8, (division >> 8) as u8, division as u8]);
+        for track in tracks {
+            bytes.extend_from_slice(b"MTrk");
+            bytes.extend_from_slice(&[0, 0, (track.len() >> 8) as u8, track.len() as u8]);
+            bytes.extend_from_slice(track);
+        }
+
+        bytes
+    }
+
+    /// C4 then E4, each for a quarter note at 96 ticks per quarter
+    const NOTES: &[u8] = &[
+        0x00, 0x90, 60, 100,
+        0x60, 0x80, 60, 64,
+        0x00, 0x90, 64, 100,
+        0x60, 0x80, 64, 64,
+        0x00, 0xFF, 0x2F, 0x00
+    ];
+
+    fn lenient() -> LoadOptions {
+        LoadOptions { lenient: true, ..LoadOptions::default() }
+    }
+
+    /// Return the notes of the first track as (is a Play event, tone) pairs
+    fn notes(song: &Song) -> Vec<(bool, u8)> {
+        song.tracks[0].events().iter().filter_map(|e| match *e {
+            Event::Play { tone, .. } => Some((true, tone)),
+            Event::Stop { tone, .. } => Some((false, tone)),
+            Event::Wait(_) => None
+        }).collect()
+    }
+
+    #[test]
+    fn running_status() {
+        let track = [
+            0x00, 0x90, 60, 100,
+            0x00, 64, 100,
+            0x60, 60, 0,
+            0x00, 64, 0,
+            0x00, 0xFF, 0x2F, 0x00
+        ];
+
+        let song = Song::from_bytes(&midi_file(96, &[&track])).unwrap();
+        assert_eq!(notes(&song), vec![(true, 60), (true, 64), (false, 60), (false, 64)]);
+    }
+
+    #[test]
+    fn data_byte_without_running_status() {
+        let track = [0x00, 60, 100, 0x00, 0xFF, 0x2F, 0x00];
+        match Song::from_bytes(&midi_file(96, &[&track])) {
+            Err(SongError::MalformedTrack { track: 0, .. }) => (),
+            _ => panic!("expected a malformed track")
+        }
+    }
+
+    #[test]
+    fn tempo_change() {
+        // C4 at 120 bpm, then E4 at 60 bpm
+        let track = [
+            0x00, 0x90, 60, 100,
+            0x60, 0x80, 60, 64,
+            0x00, 0xFF, 0x51, 0x03, 0x0F, 0x42, 0x40,
+            0x00, 0x90, 64, 100,
+            0x60, 0x80, 64, 64,
+            0x00, 0xFF, 0x2F, 0x00
+        ];
+
+        let song = Song::from_bytes(&midi_file(96, &[&track])).unwrap();
+        let waits: Vec<_> = song.tracks[0].events().iter().filter_map(|e| match *e {
+            Event::Wait(time) => Some(time),
+            _ => None
+        }).collect();
+
+        assert_eq!(waits, vec![500_000, 1_000_000]);
+    }
+
+    #[test]
+    fn invalid_tempo() {
+        for tempo in &[&[0xFF, 0x51, 0x00][..], &[0xFF, 0x51, 0x03, 0, 0, 0][..], &[0xFF, 0x51, 0x02, 0x07, 0xA1][..]] {
+            let mut track = vec![0x00];
+            track.extend_from_slice(tempo);
+            track.extend_from_slice(NOTES);
+
+            match Song::from_bytes(&midi_file(96, &[&track])) {
+                Err(SongError::MalformedTrack { track: 0, .. }) => (),
+                _ => panic!("tempo {:?} should be rejected", tempo)
+            }
+
+            // Lenient loading ignores the event, keeping the default tempo
+            let song = Song::from_reader_with(&midi_file(96, &[&track])[..], &lenient()).unwrap();
+            assert!(song.tempo_map.changes().is_empty());
+            assert_eq!(notes(&song).len(), 4);
+        }
+    }
+
+    #[test]
+    fn division() {
+        let song = Song::from_bytes(&midi_file(0xE728, &[NOTES])).unwrap();
+        assert_eq!(song.time_base, TimeBase::Smpte { fps: 25, ticks_per_frame: 40 });
+        assert_eq!(TimeBase::from_division(0xE728).map(TimeBase::to_division), Some(0xE728));
+        assert_eq!(TimeBase::from_division(0x8000), None);
+        assert_eq!(TimeBase::from_division(0x8004), None);
+
+        for &division in &[0, 0x8004, 0xE700, 0xE928] {
+            match Song::from_bytes(&midi_file(division, &[NOTES])) {
+                Err(SongError::MalformedHeader(_)) => (),
+                _ => panic!("division {:#06x} should be rejected", division)
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_track() {
+        let mut bytes = midi_file(96, &[NOTES]);
+        let len = bytes.len();
+        bytes.truncate(len - 7);
+
+        match Song::from_bytes(&bytes) {
+            Err(SongError::TruncatedTrack { track: 0 }) => (),
+            _ => panic!("expected a truncated track")
+        }
+
+        let song = Song::from_reader_with(&bytes[..], &lenient()).unwrap();
+        assert_eq!(notes(&song), vec![(true, 60), (false, 60), (true, 64)]);
+        assert_eq!(song.warnings.len(), 1);
+    }
+
+    #[test]
+    fn truncated_header() {
+        match Song::from_bytes(&midi_file(96, &[])[..10]) {
+            Err(SongError::MalformedHeader(_)) => (),
+            _ => panic!("expected a malformed header")
+        }
+    }
+
+    #[test]
+    fn huge_delta_times() {
+        // Each delta time is the largest a VLQ can hold, so they overflow a u32 together
+        let mut track = Vec::new();
+        for _ in 0..17 {
+            track.extend_from_slice(&[0xFF, 0xFF, 0xFF, 0x7F, 0xFF, 0x01, 0x00]);
+        }
+
+        match Song::from_bytes(&midi_file(96, &[&track])) {
+            Err(SongError::MalformedTrack { track: 0, .. }) => (),
+            _ => panic!("expected a malformed track")
+        }
+    }
+
+    #[test]
+    fn long_waits() {
+        // A note held for 2^28 - 1 ticks, which is longer than a u32 of microseconds
+        let track = [
+            0x00, 0x90, 60, 100,
+            0xFF, 0xFF, 0xFF, 0x7F, 0x80, 60, 64,
+            0x00, 0xFF, 0x2F, 0x00
+        ];
+
+        let song = Song::from_bytes(&midi_file(96, &[&track])).unwrap();
+        let total: u64 = song.tracks[0].events().iter().filter_map(|e| match *e {
+            Event::Wait(time) => Some(time as u64),
+            _ => None
+        }).sum();
+
+        assert_eq!(total, song.tempo_map.ticks_to_micros(0x0FFF_FFFF, song.time_base));
+        assert!(total > u32::MAX as u64);
+    }
+
+    #[test]
+    fn invalid_chunks() {
+        // Events right after the header, without a track chunk
+        let mut bytes = midi_file(96, &[NOTES]);
+        bytes.splice(14..14, NOTES.iter().cloned());
+        match Song::from_bytes(&bytes) {
+            Err(SongError::EventBeforeTrack) => (),
+            _ => panic!("expected an event before the first track")
+        }
+
+        // Garbage between two tracks
+        let mut bytes = midi_file(96, &[NOTES, NOTES]);
+        let second = bytes.len() - NOTES.len() - 8;
+        bytes.splice(second..second, vec![0; 8]);
+        match Song::from_bytes(&bytes) {
+            Err(SongError::InvalidChunk { after_track: 0, tag: [0, 0, 0, 0] }) => (),
+            _ => panic!("expected an invalid chunk")
+        }
+
+        let song = Song::from_reader_with(&bytes[..], &lenient()).unwrap();
+        assert_eq!(song.tracks.len(), 1);
+    }
+
+    #[test]
+    fn trailing_data() {
+        for &padding in &[&[0u8; 16][..], &[0, 0, 0][..], &b"MTrk\0\0\0\0"[..]] {
+            let mut bytes = midi_file(96, &[NOTES]);
+            bytes.extend_from_slice(padding);
+
+            let song = Song::from_bytes(&bytes).unwrap();
+            assert_eq!(song.tracks.len(), 1);
+            assert_eq!(notes(&song).len(), 4);
+        }
+    }
+}
@@ -0,0 +1,225 @@
+//! Export of songs as Standard MIDI Files
+
+use std::io::{self, Write};
+
+use byteorder::{BigEndian, WriteBytesExt};
+use ghakuf::messages::{Message, MetaEvent, MidiEvent};
+
+use song::{self, Event, Song, Track};
+use tempo::TimeBase;
+
+/// The way tracks are laid out in a Standard MIDI File
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MidiFormat {
+    /// Format 0: all tracks are merged into a single track chunk
+    SingleTrack,
+    /// Format 1: one track chunk per track, with the tempo changes in the first one
+    MultiTrack
+}
+
+/// Write the song to `dest` as a Standard MIDI File
+pub fn write_song<W: Write>(song: &Song, format: MidiFormat, mut dest: W) -> io::Result<()> {
+    let mut tempo = tempo_messages(song);
+    tempo.extend(time_signature_messages(song));
+    let chunks = match format {
+        MidiFormat::SingleTrack => {
+            // The merged track has no program, so the program of each track is kept on its channel
+            let merged = song::merge_tracks(song.tracks.clone());
+            let mut chunk = tempo;
+            chunk.extend(song.tracks.iter().flat_map(program_message));
+            chunk.extend(track_messages(song, &merged));
+            vec![chunk]
+        }
+        MidiFormat::MultiTrack => {
+            // Adding a separate chunk for the tempo would shift the track numbers
+            let mut chunks: Vec<_> = song.tracks.iter().map(|t| track_messages(song, t)).collect();
+            match chunks.first_mut() {
+                Some(chunk) => chunk.extend(tempo),
+                None => chunks.push(tempo)
+            }
+
+            chunks
+        }
+    };
+
+    dest.write_all(b"MThd")?;
+    dest.write_u32::<BigEndian>(6)?;
+    dest.write_u16::<BigEndian>(if format == MidiFormat::SingleTrack { 0 } else { 1 })?;
+    dest.write_u16::<BigEndian>(chunks.len() as u16)?;
+    dest.write_u16::<BigEndian>(song.time_base.to_division())?;
+
+    for chunk in chunks {
+        write_chunk(&mut dest, chunk)?;
+    }
+
+    dest.flush()
+}
+
+/// Return the Set Tempo events of the song, along with their position in ticks
+fn tempo_messages(song: &Song) -> Vec<(u32, Message)> {
+    // Tempo changes have no effect when using SMPTE timing
+    if let TimeBase::Smpte { .. } = song.time_base {
+        return Vec::new();
+    }
+
+    song.tempo_map.changes().iter().map(|change| {
+        let tempo = change.micros_per_quarter;
+        let data = vec![(tempo >> 16) as u8, (tempo >> 8) as u8, tempo as u8];
+        (change.tick, Message::MetaEvent { delta_time: 0, event: MetaEvent::SetTempo, data })
+    }).collect()
+}
+
+/// Return the Time Signature events of the song, along with their position in ticks
+fn time_signature_messages(song: &Song) -> Vec<(u32, Message)> {
+    song.time_signatures.iter().map(|signature| {
+        let power = signature.denominator.trailing_zeros() as u8;
+        // A metronome click every quarter note, and 8 32nd notes per quarter note
+        let data = vec![signature.numerator, power, 24, 8];
+        (signature.tick, Message::MetaEvent { delta_time: 0, event: MetaEvent::TimeSignature, data })
+    }).collect()
+}
+
+/// Return the events of the track, along with their position in ticks
+fn track_messages(song: &Song, track: &Track) -> Vec<(u32, Message)> {
+    let mut messages = Vec::new();
+    if let Some(ref name) = track.name {
+        let data = name.as_bytes().to_vec();
+        messages.push((0, Message::MetaEvent { delta_time: 0, event: MetaEvent::SequenceOrTrackName, data }));
+    }
+
+    messages.extend(program_message(track));
+
+    let mut micros = 0;
+    for event in track.events() {
+        let tick = song.tempo_map.micros_to_ticks(micros, song.time_base);
+        let event = match *event {
+            Event::Play { tone, velocity, channel, .. } => MidiEvent::NoteOn { ch: channel, note: tone, velocity },
+            Event::Stop { tone, channel, .. } => MidiEvent::NoteOff { ch: channel, note: tone, velocity: 64 },
+            Event::Wait(time) => {
+                micros += time as u64;
+                continue;
+            }
+        };
+
+        messages.push((tick, Message::MidiEvent { delta_time: 0, event }));
+    }
+
+    messages
+}
+
+/// Return the Program Change event of the track, on the first channel used by its notes
+fn program_message(track: &Track) -> Option<(u32, Message)> {
+    track.program.map(|program| {
+        let ch = track.channels().first().cloned().unwrap_or(0);
+        (0, Message::MidiEvent { delta_time: 0, event: MidiEvent::ProgramChange { ch, program } })
+    })
+}
+
+/// Write a track chunk containing the given messages, which may be out of order
+fn write_chunk<W: Write>(dest: &mut W, mut messages: Vec<(u32, Message)>) -> io::Result<()> {
+    // Stable sort, so simultaneous events keep their relative order
+    messages.sort_by_key(|&(tick, _)| tick);
+
+    let end = messages.last().map_or(0, |&(tick, _)| tick);
+    messages.push((end, Message::MetaEvent { delta_time: 0, event: MetaEvent::EndOfTrack, data: Vec::new() }));
+
+    let mut data = Vec::new();
+    let mut last_tick = 0;
+    for (tick, mut message) in messages {
+        match message {
+            Message::MetaEvent { ref mut delta_time, .. }
+            | Message::MidiEvent { ref mut delta_time, .. }
+            | Message::SysExEvent { ref mut delta_time, .. } => *delta_time = tick - last_tick,
+            Message::TrackChange => ()
+        }
+
+        data.extend(message.binary());
+        last_tick = tick;
+    }
+
+    dest.write_all(b"MTrk")?;
+    dest.write_u32::<BigEndian>(data.len() as u32)?;
+    dest.write_all(&data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MidiFormat;
+    use song::{Event, LoadOptions, Song};
+
+    /// Two tracks at 96 ticks per quarter and 100 bpm: an electric piano playing C4 on channel 1,
+    /// then a flute playing E4 on channel 2
+    const SONG: &[u8] = &[
+        b'M', b'T', b'h', b'd', 0, 0, 0, 6, 0, 1, 0, 2, 0, 96,
+        b'M', b'T', b'r', b'k', 0, 0, 0, 22,
+        0x00, 0xFF, 0x51, 0x03, 0x09, 0x27, 0xC0,
+        0x00, 0xC0, 5,
+        0x00, 0x90, 60, 100,
+        0x60, 0x80, 60, 64,
+        0x00, 0xFF, 0x2F, 0x00,
+        b'M', b'T', b'r', b'k', 0, 0, 0, 24,
+        0x00, 0xFF, 0x03, 0x05, b'F', b'l', b'u', b't', b'e',
+        0x00, 0xC1, 73,
+        0x60, 0x91, 64, 90,
+        0x60, 0x81, 64, 64,
+        0x00, 0xFF, 0x2F, 0x00
+    ];
+
+    fn roundtrip(song: &Song, format: MidiFormat) -> Song {
+        let mut bytes = Vec::new();
+        song.write_midi(&mut bytes, format).unwrap();
+        Song::from_bytes(&bytes).unwrap()
+    }
+
+    /// Return the events of the track as (microseconds since the start, tone, channel,
+    /// velocity or `None` for `Stop` events)
+    fn notes(song: &Song, track: usize) -> Vec<(u64, u8, u8, Option<u8>)> {
+        let mut micros = 0;
+        song.tracks[track].events().iter().filter_map(|e| match *e {
+            Event::Play { tone, velocity, channel, .. } => Some((micros, tone, channel, Some(velocity))),
+            Event::Stop { tone, channel, .. } => Some((micros, tone, channel, None)),
+            Event::Wait(time) => {
+                micros += time as u64;
+                None
+            }
+        }).collect()
+    }
+
+    #[test]
+    fn multi_track() {
+        let song = Song::from_bytes(SONG).unwrap();
+        let copy = roundtrip(&song, MidiFormat::MultiTrack);
+
+        assert_eq!(copy.tracks.len(), 2);
+        assert_eq!(copy.tempo_map.changes(), song.tempo_map.changes());
+        for i in 0..2 {
+            assert_eq!(copy.tracks[i].name, song.tracks[i].name);
+            assert_eq!(copy.tracks[i].program, song.tracks[i].program);
+            assert_eq!(notes(&copy, i), notes(&song, i));
+        }
+    }
+
+    #[test]
+    fn single_track() {
+        let song = Song::from_bytes(SONG).unwrap();
+        let mut bytes = Vec::new();
+        song.write_midi(&mut bytes, MidiFormat::SingleTrack).unwrap();
+        let copy = Song::from_bytes(&bytes).unwrap();
+
+        assert_eq!(copy.tracks.len(), 1);
+        assert_eq!(copy.tracks[0].program, Some(5));
+        assert_eq!(copy.tempo_map.changes(), song.tempo_map.changes());
+        assert_eq!(notes(&copy, 0), vec![
+            (0, 60, 0, Some(100)),
+            (600_000, 60, 0, None),
+            (600_000, 64, 1, Some(90)),
+            (1_200_000, 64, 1, None)
+        ]);
+
+        // Splitting the channels gives back the program of each track
+        let options = LoadOptions { split_channels: true, ..LoadOptions::default() };
+        let split = Song::from_reader_with(&bytes[..], &options).unwrap();
+        let programs: Vec<_> = split.tracks.iter().map(|t| t.program).collect();
+        assert_eq!(programs, vec![Some(5), Some(73)]);
+    }
+}
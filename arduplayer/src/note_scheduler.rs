@@ -1,50 +1,445 @@
+use voice::{Voice, VoiceAllocator};
+
 /// A data structure to keep track of the buzzers that are free and
 /// assign them to new notes when required
 pub struct NoteScheduler {
-    pub playing: Vec<(u8, usize)>,
-    buzzers: Vec<bool>,
+    /// The voice playing on each buzzer, if any
+    buzzers: Vec<Option<Voice>>,
+    allocator: Box<dyn VoiceAllocator + Send>,
+    /// Pairs of track number and priority, see `PlayerOptions::priorities`
+    priorities: Vec<(usize, u8)>,
+    /// Pairs of track number and buzzer reserved for it, see `PlayerOptions::reserved`
+    reserved: Vec<(usize, u8)>,
+    /// Whether tracks with reserved buzzers may use the shared ones when theirs are busy
+    shared_fallback: bool,
+    /// Amount of buzzers used to cycle through the excess notes, if arpeggiation is enabled
+    arpeggio: Option<u8>,
+    /// Notes that are active but have no buzzer, waiting for their turn to sound
+    pending: Vec<Voice>,
+    /// Sequence number for the next voice
+    next_voice: u64,
     // The variables below are used for debugging purposes
     pub playing_max_count: u8,
     pub wrong_count: u32
 }
 
 impl NoteScheduler {
-    /// Create a new scheduler with the given amount of buzzers
-    pub fn new(buzzers: u8) -> NoteScheduler {
+    /// Create a new scheduler with the given amount of buzzers, assigning them to
+    /// notes according to `allocator`
+    pub fn new(buzzers: u8, allocator: Box<dyn VoiceAllocator + Send>) -> NoteScheduler {
         NoteScheduler {
-            playing: Vec::with_capacity(buzzers as usize),
-            buzzers: vec![false; buzzers as usize],
+            buzzers: vec![None; buzzers as usize],
+            allocator,
+            priorities: Vec::new(),
+            reserved: Vec::new(),
+            shared_fallback: false,
+            arpeggio: None,
+            pending: Vec::new(),
+            next_voice: 0,
             playing_max_count: 0,
             wrong_count: 0
         }
     }
 
-    /// Register the note as playing and return the buzzer it should be played in
-    pub fn start_note(&mut self, note: u8) -> Option<u8> {
-        // Find an available buzzer and use it
-        if let Some((index, buzzer)) = self.buzzers.iter_mut().enumerate().find(|&(_, &mut in_use)| !in_use) {
-            *buzzer = true;
-            self.playing.push((note, index));
+    /// Return the amount of buzzers managed by this scheduler
+    pub fn buzzer_count(&self) -> u8 {
+        self.buzzers.len() as u8
+    }
+
+    /// Set the priority of each track, where unlisted tracks have priority 0
+    pub fn set_priorities(&mut self, priorities: &[(usize, u8)]) {
+        self.priorities = priorities.to_vec();
+    }
+
+    /// Return the priority of the given track
+    fn priority(&self, track: usize) -> u8 {
+        self.priorities.iter().find(|&&(t, _)| t == track).map_or(0, |&(_, p)| p)
+    }
+
+    /// Reserve buzzers for specific tracks, given as pairs of track number and buzzer
+    ///
+    /// Tracks with reserved buzzers only play on them, unless `shared_fallback` is set,
+    /// in which case they use the shared buzzers when all their own are busy. The rest of
+    /// the tracks only play on the buzzers that are not reserved
+    pub fn set_reserved(&mut self, reserved: &[(usize, u8)], shared_fallback: bool) {
+        let count = self.buzzer_count();
+        self.reserved = reserved.iter().cloned().filter(|&(_, buzzer)| buzzer < count).collect();
+        self.shared_fallback = shared_fallback;
+    }
+
+    /// Enable arpeggiation on the given amount of buzzers, or disable it with `None`
+    ///
+    /// When enabled, notes that don't get a buzzer (or lose theirs) are kept as pending
+    /// instead of forgotten. Each call to `arpeggiate` gives them free buzzers or rotates
+    /// them through the last `buzzers` buzzers
+    pub fn set_arpeggio(&mut self, buzzers: Option<u8>) {
+        self.arpeggio = buzzers;
+        if buzzers.is_none() {
+            self.pending.clear();
+        }
+    }
 
-            // Keep track of the amount of notes being played at the same time
-            self.playing_max_count = ::std::cmp::max(self.playing.len() as u8, self.playing_max_count);
+    /// Return whether the given track may play on the buzzer, if its own are busy
+    fn allowed(&self, track: usize, buzzer: usize) -> bool {
+        let mut owners = self.reserved.iter().filter(|&&(_, b)| b as usize == buzzer);
+        let has_own = self.reserved.iter().any(|&(t, _)| t == track);
+        match owners.next() {
+            Some(_) => self.reserved.contains(&(track, buzzer as u8)),
+            None => !has_own || self.shared_fallback
+        }
+    }
+
+    /// Return the buzzers the given track may play on
+    fn candidates(&self, track: usize) -> Vec<usize> {
+        let own: Vec<_> = self.reserved.iter().filter(|&&(t, _)| t == track).map(|&(_, b)| b as usize).collect();
+        let shared = || (0..self.buzzers.len()).filter(|&i| !self.reserved.iter().any(|&(_, b)| b as usize == i));
 
-            Some(index as u8)
+        let own_busy = own.iter().all(|&i| self.buzzers[i].is_some());
+        if own.is_empty() || (self.shared_fallback && own_busy && shared().any(|i| self.buzzers[i].is_none())) {
+            shared().collect()
         } else {
-            None
+            own
+        }
+    }
+
+    /// Return the buzzer playing the tone started by the given track and channel, if any
+    fn find(&self, tone: u8, channel: u8, track: usize) -> Option<usize> {
+        self.buzzers.iter().position(|b| match *b {
+            Some(voice) => voice.tone == tone && voice.channel == channel && voice.track == track,
+            None => false
+        })
+    }
+
+    /// Return the voices that are currently playing
+    pub fn playing<'a>(&'a self) -> impl Iterator<Item=&'a Voice> + 'a {
+        self.buzzers.iter().filter_map(|b| b.as_ref())
+    }
+
+    /// Return the voices that are currently playing, along with their buzzer
+    pub fn assigned<'a>(&'a self) -> impl Iterator<Item=(u8, &'a Voice)> + 'a {
+        self.buzzers.iter().enumerate().filter_map(|(i, b)| b.as_ref().map(|v| (i as u8, v)))
+    }
+
+    /// Register the note as playing and return the buzzer it should be played in
+    ///
+    /// If a busy buzzer is stolen, the note playing on it is forgotten, so stopping it
+    /// later has no effect. The allocator decides which note to steal, but it is
+    /// overruled by the track priorities: a note never steals a buzzer from a higher
+    /// priority track, and it steals one from a lower priority track rather than
+    /// being dropped
+    ///
+    /// The note is played for the given `tone` of the song, which identifies the voice.
+    /// Notes of a transposed song that end up with the same pitch (e.g. when folding them
+    /// into range) still get their own buzzer
+    pub fn start_tone(&mut self, tone: u8, note: u8, velocity: u8, channel: u8, track: usize) -> Option<u8> {
+        // A re-trigger of a sounding note restarts it on the same buzzer, since a second
+        // buzzer playing the same frequency would sound the same
+        if let Some(index) = self.find(tone, channel, track) {
+            let voice = self.buzzers[index].as_mut().unwrap();
+            voice.note = note;
+            voice.velocity = velocity;
+            voice.started = self.next_voice;
+            self.next_voice += 1;
+            return Some(index as u8);
+        }
+
+        if let Some(voice) = self.pending.iter_mut().find(|v| v.tone == tone && v.channel == channel && v.track == track) {
+            voice.note = note;
+            voice.velocity = velocity;
+            return None;
+        }
+
+        let priority = self.priority(track);
+        let voice = Voice { tone, note, velocity, channel, track, priority, started: self.next_voice };
+        self.next_voice += 1;
+
+        // The allocator only sees the buzzers the track may play on
+        let candidates = self.candidates(track);
+        let buzzers: Vec<_> = candidates.iter().map(|&i| self.buzzers[i]).collect();
+        // An index out of bounds is a bug in the allocator, which we treat as dropping the note
+        let chosen = self.allocator.allocate(&voice, &buzzers).filter(|&i| match buzzers.get(i) {
+            Some(&Some(playing)) => playing.priority <= priority,
+            Some(&None) => true,
+            None => false
+        });
+
+        // Fall back to the oldest note of the lowest priority track, if it is lower than ours
+        let index = chosen.map(|i| candidates[i]).or_else(|| candidates.iter()
+            .filter_map(|&i| self.buzzers[i].map(|v| (i, v)))
+            .filter(|&(_, v)| v.priority < priority)
+            .min_by_key(|&(_, v)| (v.priority, v.started))
+            .map(|(i, _)| i));
+
+        let index = match index {
+            Some(index) => index,
+            None => {
+                if self.arpeggio.is_some() {
+                    self.pending.push(voice);
+                }
+                return None;
+            }
+        };
+
+        if let Some(stolen) = self.buzzers[index] {
+            if self.arpeggio.is_some() {
+                self.pending.push(stolen);
+            }
         }
+
+        self.buzzers[index] = Some(voice);
+
+        // Keep track of the amount of notes being played at the same time
+        self.playing_max_count = ::std::cmp::max(self.playing().count() as u8, self.playing_max_count);
+
+        Some(index as u8)
     }
 
-    /// Register that the note has stopped playing and return the buzzer it was playing on
-    pub fn stop_note(&mut self, note: u8) -> Option<u8> {
+    /// Register that the tone has stopped playing and return the buzzer it was playing on
+    ///
+    /// Only the tone started by the same track and channel is stopped. Since re-triggers
+    /// share a buzzer, the first note off of a re-triggered note stops it
+    pub fn stop_note(&mut self, tone: u8, channel: u8, track: usize) -> Option<u8> {
+        if let Some(pos) = self.pending.iter().position(|v| v.tone == tone && v.channel == channel && v.track == track) {
+            self.pending.remove(pos);
+            return None;
+        }
+
         // Ensure the note is already playing, and remove it from the buzzer
-        if let Some(pos) = self.playing.iter().position(|&(n, _)| n == note) {
-            let (_, buzzer) = self.playing.swap_remove(pos);
-            // buzzer is no longer in use
-            self.buzzers[buzzer] = false;
-            Some(buzzer as u8)
-        } else {
-            None
+        let index = self.find(tone, channel, track)?;
+        // buzzer is no longer in use
+        self.buzzers[index] = None;
+        Some(index as u8)
+    }
+
+    /// Change the note played for a tone, keeping its buzzer, and return the buzzer
+    ///
+    /// Returns `None` if the tone is not playing or if it is pending
+    pub fn retune(&mut self, tone: u8, channel: u8, track: usize, new_note: u8) -> Option<u8> {
+        if let Some(voice) = self.pending.iter_mut().find(|v| v.tone == tone && v.channel == channel && v.track == track) {
+            voice.note = new_note;
+            return None;
+        }
+
+        let index = self.find(tone, channel, track)?;
+        self.buzzers[index].as_mut().unwrap().note = new_note;
+        Some(index as u8)
+    }
+
+    /// Forget all notes, including the pending ones
+    pub fn stop_all(&mut self) {
+        for buzzer in &mut self.buzzers {
+            *buzzer = None;
         }
+
+        self.pending.clear();
+    }
+
+    /// Move pending notes to free buzzers, and then swap the notes playing on the
+    /// arpeggio buzzers with the pending ones
+    ///
+    /// Return the buzzers that changed along with the note they must play now. This should
+    /// be called at a fixed rate when arpeggiation is enabled
+    pub fn arpeggiate(&mut self) -> Vec<(u8, u8)> {
+        let mut changes = Vec::new();
+        let arpeggio = match self.arpeggio {
+            Some(buzzers) => buzzers as usize,
+            None => return changes
+        };
+
+        for i in 0..self.buzzers.len() {
+            if self.buzzers[i].is_none() {
+                if let Some(pos) = self.pending.iter().position(|v| self.allowed(v.track, i)) {
+                    let voice = self.pending.remove(pos);
+                    self.buzzers[i] = Some(voice);
+                    changes.push((i as u8, voice.note));
+                }
+            }
+        }
+
+        // The pending notes form a queue, so all notes get the same time on the buzzers
+        let first = self.buzzers.len().saturating_sub(arpeggio);
+        for i in first..self.buzzers.len() {
+            if let Some(pos) = self.pending.iter().position(|v| self.allowed(v.track, i)) {
+                let voice = self.pending.remove(pos);
+                if let Some(previous) = self.buzzers[i] {
+                    self.pending.push(previous);
+                }
+
+                self.buzzers[i] = Some(voice);
+                changes.push((i as u8, voice.note));
+            }
+        }
+
+        changes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::NoteScheduler;
+    use voice::{Voice, VoiceAllocator, VoicePolicy};
+
+    fn scheduler(buzzers: u8, policy: VoicePolicy) -> NoteScheduler {
+        NoteScheduler::new(buzzers, Box::new(policy))
+    }
+
+    #[test]
+    fn same_note_on_two_tracks() {
+        let mut s = scheduler(2, VoicePolicy::DropNew);
+        assert_eq!(s.start_tone(60, 60, 100, 0, 0), Some(0));
+        assert_eq!(s.start_tone(60, 60, 100, 0, 1), Some(1));
+
+        // Stopping the note of the second track must not silence the first one
+        assert_eq!(s.stop_note(60, 0, 1), Some(1));
+        assert_eq!(s.playing().count(), 1);
+        assert_eq!(s.stop_note(60, 0, 0), Some(0));
+        assert_eq!(s.playing().count(), 0);
+    }
+
+    #[test]
+    fn tones_with_the_same_pitch() {
+        // Two octaves folded into the same pitch are different notes of the song
+        let mut s = scheduler(2, VoicePolicy::DropNew);
+        assert_eq!(s.start_tone(96, 96, 100, 0, 0), Some(0));
+        assert_eq!(s.start_tone(108, 96, 100, 0, 0), Some(1));
+        assert_eq!(s.stop_note(108, 0, 0), Some(1));
+        assert_eq!(s.playing().map(|v| v.tone).collect::<Vec<_>>(), vec![96]);
+        assert_eq!(s.stop_note(96, 0, 0), Some(0));
+    }
+
+    #[test]
+    fn same_note_on_two_channels() {
+        let mut s = scheduler(2, VoicePolicy::DropNew);
+        assert_eq!(s.start_tone(60, 60, 100, 0, 0), Some(0));
+        assert_eq!(s.start_tone(60, 60, 100, 1, 0), Some(1));
+        assert_eq!(s.stop_note(60, 1, 0), Some(1));
+        assert_eq!(s.stop_note(60, 0, 0), Some(0));
+    }
+
+    #[test]
+    fn retrigger_reuses_buzzer() {
+        let mut s = scheduler(2, VoicePolicy::DropNew);
+        assert_eq!(s.start_tone(60, 60, 100, 0, 0), Some(0));
+        assert_eq!(s.start_tone(60, 60, 80, 0, 0), Some(0));
+        assert_eq!(s.playing().count(), 1);
+        assert_eq!(s.playing().next().unwrap().velocity, 80);
+
+        // The first note off stops the note, the second one is ignored
+        assert_eq!(s.stop_note(60, 0, 0), Some(0));
+        assert_eq!(s.stop_note(60, 0, 0), None);
+    }
+
+    #[test]
+    fn retrigger_when_full() {
+        let mut s = scheduler(1, VoicePolicy::DropNew);
+        assert_eq!(s.start_tone(60, 60, 100, 0, 0), Some(0));
+        assert_eq!(s.start_tone(60, 60, 100, 0, 0), Some(0));
+        assert_eq!(s.start_tone(62, 62, 100, 0, 0), None);
+    }
+
+    #[test]
+    fn stop_unknown_note() {
+        let mut s = scheduler(2, VoicePolicy::DropNew);
+        assert_eq!(s.stop_note(60, 0, 0), None);
+        s.start_tone(60, 60, 100, 0, 0);
+        assert_eq!(s.stop_note(61, 0, 0), None);
+        assert_eq!(s.stop_note(60, 0, 1), None);
+        assert_eq!(s.playing().count(), 1);
+    }
+
+    #[test]
+    fn stolen_note_is_forgotten() {
+        let mut s = scheduler(1, VoicePolicy::StealOldest);
+        assert_eq!(s.start_tone(60, 60, 100, 0, 0), Some(0));
+        assert_eq!(s.start_tone(64, 64, 100, 0, 1), Some(0));
+
+        // The note off of the stolen note must not silence the new one
+        assert_eq!(s.stop_note(60, 0, 0), None);
+        assert_eq!(s.stop_note(64, 0, 1), Some(0));
+    }
+
+    #[test]
+    fn invalid_allocation() {
+        struct Broken;
+        impl VoiceAllocator for Broken {
+            fn allocate(&mut self, _voice: &Voice, buzzers: &[Option<Voice>]) -> Option<usize> {
+                Some(buzzers.len())
+            }
+        }
+
+        let mut s = NoteScheduler::new(2, Box::new(Broken));
+        assert_eq!(s.start_tone(60, 60, 100, 0, 0), None);
+        assert_eq!(s.playing().count(), 0);
+    }
+
+    #[test]
+    fn quietest_track_tie() {
+        let mut s = scheduler(3, VoicePolicy::StealQuietestTrack);
+        assert_eq!(s.start_tone(60, 60, 100, 0, 2), Some(0));
+        assert_eq!(s.start_tone(64, 64, 100, 0, 0), Some(1));
+        assert_eq!(s.start_tone(67, 67, 100, 0, 1), Some(2));
+
+        // All tracks are as loud, so the one with the lowest index loses its note
+        assert_eq!(s.start_tone(72, 72, 100, 0, 3), Some(1));
+    }
+
+    #[test]
+    fn priorities() {
+        let mut s = scheduler(1, VoicePolicy::DropNew);
+        s.set_priorities(&[(0, 1)]);
+        assert_eq!(s.start_tone(48, 48, 100, 0, 1), Some(0));
+
+        // The higher priority track steals the buzzer, even though the policy drops notes
+        assert_eq!(s.start_tone(60, 60, 100, 0, 0), Some(0));
+        assert_eq!(s.start_tone(50, 50, 100, 0, 1), None);
+        assert_eq!(s.stop_note(60, 0, 0), Some(0));
+    }
+
+    #[test]
+    fn reserved_buzzers() {
+        let mut s = scheduler(3, VoicePolicy::DropNew);
+        s.set_reserved(&[(1, 2)], false);
+
+        // The bass track only plays on its own buzzer, and the others never touch it
+        assert_eq!(s.start_tone(36, 36, 100, 0, 1), Some(2));
+        assert_eq!(s.start_tone(38, 38, 100, 0, 1), None);
+        assert_eq!(s.start_tone(60, 60, 100, 0, 0), Some(0));
+        assert_eq!(s.start_tone(62, 62, 100, 0, 0), Some(1));
+        assert_eq!(s.start_tone(64, 64, 100, 0, 0), None);
+    }
+
+    #[test]
+    fn reserved_buzzers_with_fallback() {
+        let mut s = scheduler(3, VoicePolicy::DropNew);
+        s.set_reserved(&[(1, 2)], true);
+        assert_eq!(s.start_tone(36, 36, 100, 0, 1), Some(2));
+        assert_eq!(s.start_tone(38, 38, 100, 0, 1), Some(0));
+        assert_eq!(s.start_tone(60, 60, 100, 0, 0), Some(1));
+        assert_eq!(s.start_tone(40, 40, 100, 0, 1), None);
+    }
+
+    #[test]
+    fn arpeggio() {
+        let mut s = scheduler(2, VoicePolicy::DropNew);
+        s.set_arpeggio(Some(1));
+        assert_eq!(s.start_tone(48, 48, 100, 0, 0), Some(0));
+        assert_eq!(s.start_tone(60, 60, 100, 0, 0), Some(1));
+        assert_eq!(s.start_tone(64, 64, 100, 0, 0), None);
+        assert_eq!(s.start_tone(67, 67, 100, 0, 0), None);
+
+        // The excess notes take turns on the last buzzer
+        assert_eq!(s.arpeggiate(), vec![(1, 64)]);
+        assert_eq!(s.arpeggiate(), vec![(1, 67)]);
+        assert_eq!(s.arpeggiate(), vec![(1, 60)]);
+
+        // Stopping a pending note removes it from the rotation
+        assert_eq!(s.stop_note(64, 0, 0), None);
+        assert_eq!(s.arpeggiate(), vec![(1, 67)]);
+        assert_eq!(s.arpeggiate(), vec![(1, 60)]);
+
+        // Free buzzers are filled with pending notes
+        assert_eq!(s.stop_note(48, 0, 0), Some(0));
+        assert_eq!(s.arpeggiate(), vec![(0, 67)]);
+        assert_eq!(s.arpeggiate(), vec![]);
     }
 }
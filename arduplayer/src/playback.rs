@@ -0,0 +1,488 @@
+//! Playback of songs on a background thread, controlled through a handle
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use backend::Backend;
+use player::{Player, PlayerOptions};
+use song::{Event, Song, Track};
+use util::{self, OutOfRange};
+
+/// A request sent to the playback thread
+pub(crate) enum Control {
+    Pause,
+    Resume,
+    Stop,
+    Seek(Duration),
+    SetDelayMul(f64),
+    SetTranspose(i8)
+}
+
+/// Timing of a song that has been played
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PlaybackReport {
+    /// How late each note event was played, in the order they were played
+    ///
+    /// This includes the time spent sending the event to the backend
+    pub lateness: Vec<Duration>
+}
+
+impl PlaybackReport {
+    /// Return the lateness of the latest event, or 0 if no events were played
+    pub fn max_lateness(&self) -> Duration {
+        self.lateness.iter().cloned().max().unwrap_or_default()
+    }
+
+    /// Return the average lateness of the events, or 0 if no events were played
+    pub fn mean_lateness(&self) -> Duration {
+        if self.lateness.is_empty() {
+            return Duration::from_secs(0);
+        }
+
+        self.lateness.iter().sum::<Duration>() / self.lateness.len() as u32
+    }
+}
+
+/// A handle to control a song playing on a background thread, see `Player::spawn`
+///
+/// Dropping the handle lets the song play until the end, unless it is paused, in which
+/// case it is stopped since nobody can resume it
+pub struct PlaybackHandle<B: Backend> {
+    controls: Sender<Control>,
+    finished: Arc<AtomicBool>,
+    thread: JoinHandle<(Player<B>, PlaybackReport)>
+}
+
+impl<B: Backend> PlaybackHandle<B> {
+    /// Pause the song, silencing the buzzers until it is resumed
+    pub fn pause(&self) {
+        self.send(Control::Pause);
+    }
+
+    /// Resume the song where it was paused
+    pub fn resume(&self) {
+        self.send(Control::Resume);
+    }
+
+    /// Stop the song and silence all buzzers
+    pub fn stop(&self) {
+        self.send(Control::Stop);
+    }
+
+    /// Continue playing from the given position, measured from the start of the song at its
+    /// original tempo
+    ///
+    /// Notes that started before the position and are still held at it are played too.
+    /// Seeking past the end of the looped region leaves the loop. If the song is paused,
+    /// it stays paused
+    pub fn seek(&self, position: Duration) {
+        self.send(Control::Seek(position));
+    }
+
+    /// Change the speed of the song, like `PlayerOptions::delay_mul`
+    pub fn set_delay_mul(&self, delay_mul: f64) {
+        self.send(Control::SetDelayMul(delay_mul));
+    }
+
+    /// Change the semitones to transpose all tracks by, replacing `PlayerOptions::transpose`
+    ///
+    /// Notes that are being held are re-pitched right away
+    pub fn set_transpose(&self, semitones: i8) {
+        self.send(Control::SetTranspose(semitones));
+    }
+
+    /// Return whether the song has ended or has been stopped
+    pub fn is_finished(&self) -> bool {
+        self.finished.load(Ordering::SeqCst)
+    }
+
+    /// Block until the song ends or is stopped, and return the player so it can be reused,
+    /// along with the timing of the playback
+    pub fn wait(self) -> (Player<B>, PlaybackReport) {
+        self.thread.join().expect("The playback thread panicked")
+    }
+
+    fn send(&self, control: Control) {
+        // If the song has already finished there is nothing to control, so errors are ignored
+        let _ = self.controls.send(control);
+    }
+}
+
+impl<B: Backend + Send + 'static> Player<B> {
+    /// Play the `Song` on a background thread, returning a handle to control it
+    ///
+    /// The thread owns the player until the song finishes, see `PlaybackHandle::wait`
+    pub fn spawn(mut self, song: Song, options: PlayerOptions<'static>) -> PlaybackHandle<B> {
+        let (controls, receiver) = mpsc::channel();
+        let finished = Arc::new(AtomicBool::new(false));
+
+        let thread_finished = finished.clone();
+        let thread = thread::spawn(move || {
+            let report = self.prepare(&song, &options, Some(receiver)).run(&mut self);
+
+            self.finish();
+            thread_finished.store(true, Ordering::SeqCst);
+            (self, report)
+        });
+
+        PlaybackHandle { controls, finished, thread }
+    }
+}
+
+/// The state of a song being played
+///
+/// Events are scheduled against an absolute timeline, anchored to an instant of the
+/// backend's clock, so delays in sending the events don't add up over the song
+pub(crate) struct Playback {
+    /// Note events of the song, along with their time in microseconds since the start
+    events: Vec<(u64, Event)>,
+    /// Index of the next event to play
+    next: usize,
+    delay_mul: f64,
+    /// Pairs of track number and transposition in octaves, see `PlayerOptions::tracks`
+    tracks: Vec<(usize, i8)>,
+    /// Semitones to transpose all tracks by, see `PlayerOptions::transpose`
+    transpose: i16,
+    out_of_range: OutOfRange,
+    /// Notes that have been started and not stopped yet
+    held: Vec<HeldNote>,
+    /// Period of the arpeggio, if enabled
+    arpeggio: Option<Duration>,
+    /// Position to start playing from, in microseconds
+    start: u64,
+    /// The region being looped, if any
+    looped: Option<Region>,
+    /// An instant along with the position of the song at that instant, in microseconds
+    anchor: Option<(Instant, u64)>,
+    /// Position where the song was paused, if it is paused
+    paused: Option<u64>,
+    /// Requests from the playback handle, if there is one
+    controls: Option<Receiver<Control>>,
+    stopped: bool,
+    report: PlaybackReport
+}
+
+/// A note of the song that is being held
+#[derive(Copy, Clone)]
+struct HeldNote {
+    tone: u8,
+    velocity: u8,
+    channel: u8,
+    track: usize,
+    /// The note sent to the player after transposing, if it is in range
+    sounding: Option<u8>
+}
+
+/// A looped region of the song, in microseconds
+#[derive(Copy, Clone)]
+struct Region {
+    start: u64,
+    end: u64,
+    /// Times left to go back to the start, or `None` to loop forever
+    jumps: Option<u32>
+}
+
+impl Playback {
+    /// Prepare to play the (already merged) track of the song, with the given controls if
+    /// it is played in the background
+    pub(crate) fn new(track: &Track, song: &Song, options: &PlayerOptions, arpeggio: Option<Duration>, controls: Option<Receiver<Control>>) -> Playback {
+        let mut events = Vec::with_capacity(track.events().len());
+        let mut micros = 0;
+        for &event in track.events() {
+            match event {
+                Event::Wait(time) => micros += time as u64,
+                _ => events.push((micros, event))
+            }
+        }
+
+        let start = to_micros(options.start.time(song));
+        let looped = options.looped
+            .map(|l| Region { start: to_micros(l.start.time(song)), end: to_micros(l.end.time(song)), jumps: l.count.map(|c| c.saturating_sub(1)) })
+            .filter(|r| r.start < r.end && start < r.end && r.jumps != Some(0));
+
+        Playback {
+            events,
+            next: 0,
+            delay_mul: options.delay_mul,
+            tracks: options.tracks.to_vec(),
+            transpose: options.transpose as i16,
+            out_of_range: options.out_of_range,
+            held: Vec::new(),
+            arpeggio,
+            start,
+            looped,
+            anchor: None,
+            paused: None,
+            controls,
+            stopped: false,
+            report: PlaybackReport::default()
+        }
+    }
+
+    /// Play the events until the end of the song or until it is stopped
+    pub(crate) fn run<B: Backend>(mut self, player: &mut Player<B>) -> PlaybackReport {
+        let start = self.start;
+        if start != 0 {
+            self.seek(player, start);
+        }
+
+        self.anchor = Some((player.now(), start));
+        let mut next_step = self.arpeggio.map(|period| player.now() + period);
+
+        while !self.stopped {
+            if self.paused.is_some() {
+                match self.controls.as_ref().map(|c| c.recv()) {
+                    Some(Ok(control)) => self.control(player, control),
+                    // Nobody can resume the song anymore
+                    _ => self.stopped = true
+                }
+
+                // The arpeggio starts over when resuming
+                next_step = self.arpeggio.map(|period| player.now() + period);
+                continue;
+            }
+
+            // The next event, or `None` when reaching the end of the looped region
+            let (time, event) = match (self.events.get(self.next), self.looped) {
+                (Some(&(time, _)), Some(region)) if time >= region.end => (region.end, None),
+                (Some(&(time, event)), _) => (time, Some(event)),
+                (None, Some(region)) => (region.end, None),
+                (None, None) => break
+            };
+
+            let mut deadline = self.instant_of(time);
+            let step = match next_step {
+                Some(step) if step < deadline => {
+                    deadline = step;
+                    true
+                }
+                _ => false
+            };
+
+            // Wait for the deadline, unless a control arrives before
+            let now = player.now();
+            if deadline > now {
+                let result = match self.controls {
+                    Some(ref controls) if player.is_realtime() => controls.recv_timeout(deadline - now),
+                    // The backend waits without taking real time, so only the controls that
+                    // already arrived are handled
+                    Some(ref controls) => controls.try_recv().map_err(|err| match err {
+                        TryRecvError::Empty => RecvTimeoutError::Timeout,
+                        TryRecvError::Disconnected => RecvTimeoutError::Disconnected
+                    }),
+                    None => Err(RecvTimeoutError::Timeout)
+                };
+
+                match result {
+                    Ok(control) => {
+                        self.control(player, control);
+                        continue;
+                    }
+                    Err(RecvTimeoutError::Timeout) => (),
+                    Err(RecvTimeoutError::Disconnected) => {
+                        self.controls = None;
+                        continue;
+                    }
+                }
+
+                // Let the backend wait for whatever is left, which is all of it for
+                // backends with their own clock
+                let now = player.now();
+                if deadline > now {
+                    player.wait(deadline - now);
+                }
+            }
+
+            if step {
+                player.arpeggiate();
+                next_step = self.arpeggio.map(|period| deadline + period);
+                continue;
+            }
+
+            match event {
+                Some(event) => {
+                    self.play(player, event);
+                    self.next += 1;
+                    self.report.lateness.push(player.now().duration_since(deadline));
+                }
+                None => {
+                    // Go back to the start of the loop, right on time
+                    let mut region = self.looped.take().unwrap();
+                    self.seek(player, region.start);
+                    self.anchor = Some((deadline, region.start));
+
+                    region.jumps = region.jumps.map(|j| j - 1);
+                    if region.jumps != Some(0) {
+                        self.looped = Some(region);
+                    }
+                }
+            }
+        }
+
+        self.report
+    }
+
+    fn control<B: Backend>(&mut self, player: &mut Player<B>, control: Control) {
+        match control {
+            Control::Pause => {
+                if self.paused.is_none() {
+                    self.paused = Some(self.position(player.now()));
+                    player.mute();
+                }
+            }
+            Control::Resume => {
+                if let Some(position) = self.paused.take() {
+                    self.anchor = Some((player.now(), position));
+                    player.unmute();
+                }
+            }
+            Control::Stop => self.stopped = true,
+            Control::SetDelayMul(delay_mul) => {
+                // Keep the current position, so the song goes on from it at the new speed
+                if self.paused.is_none() {
+                    self.anchor = Some((player.now(), self.position(player.now())));
+                }
+
+                self.delay_mul = delay_mul.max(0.0);
+            }
+            // While paused, the player is muted, so the notes only sound when resuming
+            Control::SetTranspose(semitones) => self.set_transpose(player, semitones),
+            Control::Seek(position) => {
+                let position = to_micros(position);
+                if let Some(region) = self.looped {
+                    if position >= region.end {
+                        self.looped = None;
+                    }
+                }
+
+                self.seek(player, position);
+                if self.paused.is_some() {
+                    self.paused = Some(position);
+                } else {
+                    self.anchor = Some((player.now(), position));
+                }
+            }
+        }
+    }
+
+    /// Move to the given position, playing the notes that should be sounding at it
+    fn seek<B: Backend>(&mut self, player: &mut Player<B>, position: u64) {
+        player.silence();
+        self.next = self.events.iter().position(|&(time, _)| time >= position).unwrap_or(self.events.len());
+
+        // Notes that stop right at the position are not held at it
+        while let Some(&(time, Event::Stop { .. })) = self.events.get(self.next) {
+            if time > position {
+                break;
+            }
+
+            self.next += 1;
+        }
+
+        // Find out which notes are held, in the order they started
+        let mut held: Vec<Event> = Vec::new();
+        for &(_, event) in &self.events[..self.next] {
+            match event {
+                Event::Play { tone, channel, track, .. } => {
+                    held.retain(|&e| !same_note(e, tone, channel, track));
+                    held.push(event);
+                }
+                Event::Stop { tone, channel, track } => held.retain(|&e| !same_note(e, tone, channel, track)),
+                Event::Wait(_) => ()
+            }
+        }
+
+        self.held.clear();
+        for event in held {
+            self.play(player, event);
+        }
+    }
+
+    /// Play a note event, applying the live transposition
+    fn play<B: Backend>(&mut self, player: &mut Player<B>, event: Event) {
+        match event {
+            Event::Play { tone, velocity, channel, track } => {
+                // A re-trigger replaces the held note
+                self.held.retain(|n| !(n.tone == tone && n.channel == channel && n.track == track));
+                let sounding = self.sounding(tone, track);
+                if let Some(note) = sounding {
+                    player.start_tone(tone, note, velocity, channel, track);
+                }
+
+                self.held.push(HeldNote { tone, velocity, channel, track, sounding });
+            }
+            Event::Stop { tone, channel, track } => {
+                // Stop the note that was started, even if the transposition changed since
+                if let Some(pos) = self.held.iter().position(|n| n.tone == tone && n.channel == channel && n.track == track) {
+                    if self.held.remove(pos).sounding.is_some() {
+                        player.stop_note(tone, channel, track);
+                    }
+                }
+            }
+            Event::Wait(_) => ()
+        }
+    }
+
+    /// Return the note to play for the given tone of a track, if it is in range
+    fn sounding(&self, tone: u8, track: usize) -> Option<u8> {
+        let octaves = self.tracks.iter().find(|&&(id, _)| id == track).map_or(0, |&(_, octaves)| octaves);
+        let semitones = octaves as i16 * 12 + self.transpose;
+        util::fit_in_range(util::transpose(tone, semitones), self.out_of_range)
+    }
+
+    /// Change the transposition of all tracks and re-pitch the held notes
+    fn set_transpose<B: Backend>(&mut self, player: &mut Player<B>, semitones: i8) {
+        self.transpose = semitones as i16;
+
+        for i in 0..self.held.len() {
+            let note = self.held[i];
+            let sounding = self.sounding(note.tone, note.track);
+            match (note.sounding, sounding) {
+                (Some(_), Some(new)) => player.retune(note.tone, note.channel, note.track, new),
+                (Some(_), None) => player.stop_note(note.tone, note.channel, note.track),
+                (None, Some(new)) => player.start_tone(note.tone, new, note.velocity, note.channel, note.track),
+                (None, None) => ()
+            }
+
+            self.held[i].sounding = sounding;
+        }
+    }
+
+    /// Return the position of the song at the given instant, in microseconds
+    fn position(&self, now: Instant) -> u64 {
+        if let Some(position) = self.paused {
+            return position;
+        }
+
+        let (instant, position) = self.anchor.unwrap();
+        let position = position + (to_micros(now.duration_since(instant)) as f64 / self.delay_mul) as u64;
+
+        // The next event has not been played yet, so the song can't be past it
+        match self.events.get(self.next) {
+            Some(&(time, _)) => ::std::cmp::min(position, time),
+            None => position
+        }
+    }
+
+    /// Return the instant at which the given position of the song should be played
+    fn instant_of(&self, time: u64) -> Instant {
+        let (instant, position) = self.anchor.unwrap();
+        let micros = time.saturating_sub(position) as f64 * self.delay_mul;
+        instant + Duration::from_micros(micros as u64)
+    }
+}
+
+/// Return whether the event is a `Play` event of the given note
+fn same_note(event: Event, note: u8, note_channel: u8, note_track: usize) -> bool {
+    match event {
+        Event::Play { tone, channel, track, .. } => tone == note && channel == note_channel && track == note_track,
+        _ => false
+    }
+}
+
+fn to_micros(duration: Duration) -> u64 {
+    duration.as_secs() * 1_000_000 + duration.subsec_micros() as u64
+}
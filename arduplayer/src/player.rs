@@ -1,96 +1,399 @@
-use std::time::Duration;
-use std::thread;
+use std::borrow::Cow;
+use std::sync::mpsc::Receiver;
+use std::time::{Duration, Instant};
 
-use serialport::{self, SerialPort};
+use serialport;
 
+use backend::Backend;
 use note_scheduler::NoteScheduler;
-use song::{self, Event, Song};
+use playback::{Control, Playback, PlaybackReport};
+use serial::SerialBackend;
+use song::{self, Song};
+use util::OutOfRange;
+use voice::{VoiceAllocator, VoicePolicy};
 
-use {serial, util};
+use {selection, util};
+
+/// Settings of the arpeggiation mode, which cycles through the notes that don't fit
+/// on the buzzers instead of dropping them
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Arpeggio {
+    /// Amount of buzzers used to cycle through the excess notes
+    pub buzzers: u8,
+    /// Time each note sounds before giving its buzzer to the next one
+    pub period: Duration
+}
+
+/// A position in a song
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Position {
+    /// Time since the start of the song, at its original tempo
+    Time(Duration),
+    /// Start of a bar, where the first bar is bar 1 (see `Song::bar_time`)
+    Bar(u32)
+}
+
+impl Position {
+    /// Return the time of this position since the start of the song, at its original tempo
+    pub fn time(self, song: &Song) -> Duration {
+        match self {
+            Position::Time(time) => time,
+            Position::Bar(bar) => song.bar_time(bar)
+        }
+    }
+}
+
+/// A region of a song that is played several times in a row
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Loop {
+    pub start: Position,
+    /// The end of the region, which is not included in it
+    pub end: Position,
+    /// Times to play the region, or `None` to play it until the song is stopped
+    pub count: Option<u32>
+}
 
 /// Options to be used when playing a MIDI file
+#[derive(Clone)]
 pub struct PlayerOptions<'a> {
-    /// Pairs of track number and desired transposition
+    /// Pairs of track number and desired transposition, in octaves
     ///
     /// Tracks that are not in this list will be ignored
-    pub tracks: &'a [(usize, i8)],
-    /// Higher means slower playback
-    pub delay_mul: f64
+    pub tracks: Cow<'a, [(usize, i8)]>,
+    /// Semitones to transpose all tracks by, on top of their own transposition
+    pub transpose: i8,
+    /// Higher means slower playback (1.0 plays the song at its original tempo)
+    pub delay_mul: f64,
+    /// What to do with notes that the buzzers can't play, after transposing
+    pub out_of_range: OutOfRange,
+    /// Pairs of track number and priority, where unlisted tracks have priority 0
+    ///
+    /// When all buzzers are busy, notes of a track can steal the buzzers of notes from
+    /// tracks with a lower priority, so the lead melody keeps sounding
+    pub priorities: Cow<'a, [(usize, u8)]>,
+    /// Pairs of track number and buzzer index, reserving the buzzer for that track
+    ///
+    /// A track may reserve several buzzers. Tracks with reserved buzzers only play on
+    /// them, and the rest of the tracks only play on the buzzers that are not reserved
+    pub reserved: Cow<'a, [(usize, u8)]>,
+    /// Let tracks with reserved buzzers play on the shared ones when theirs are busy
+    pub shared_fallback: bool,
+    /// Cycle through the notes that don't fit on the buzzers, if set
+    pub arpeggio: Option<Arpeggio>,
+    /// Position to start playing from, where notes that started earlier but are still
+    /// held are played too
+    pub start: Position,
+    /// A region to play several times before going on with the rest of the song, if set
+    ///
+    /// The loop is ignored if it ends before `start`
+    pub looped: Option<Loop>
+}
+
+impl<'a> Default for PlayerOptions<'a> {
+    fn default() -> PlayerOptions<'a> {
+        PlayerOptions {
+            tracks: Cow::Borrowed(&[]),
+            transpose: 0,
+            delay_mul: 1.0,
+            out_of_range: OutOfRange::Fold,
+            priorities: Cow::Borrowed(&[]),
+            reserved: Cow::Borrowed(&[]),
+            shared_fallback: false,
+            arpeggio: None,
+            start: Position::Time(Duration::from_secs(0)),
+            looped: None
+        }
+    }
+}
+
+impl PlayerOptions<'static> {
+    /// Options to play all tracks of the song at their original tempo, except for the
+    /// ones that are empty or contain percussion
+    pub fn for_song(song: &Song) -> PlayerOptions<'static> {
+        let tracks: Vec<_> = song.tracks.iter().enumerate()
+            .filter(|&(_, track)| !track.is_empty() && !track.is_percussion())
+            .map(|(i, _)| (i, 0))
+            .collect();
+
+        PlayerOptions { tracks: Cow::Owned(tracks), ..PlayerOptions::default() }.fit_range(song)
+    }
+
+    /// Options to play as many tracks of the song as fit on the given amount of buzzers
+    /// without dropping notes, at their original tempo
+    ///
+    /// See `Player::buzzers` to get the amount of buzzers of a player
+    pub fn fit_buzzers(song: &Song, buzzers: u8) -> PlayerOptions<'static> {
+        let tracks: Vec<_> = selection::select_tracks(song, buzzers).into_iter().map(|i| (i, 0)).collect();
+
+        PlayerOptions { tracks: Cow::Owned(tracks), ..PlayerOptions::default() }.fit_range(song)
+    }
 }
 
 impl<'a> PlayerOptions<'a> {
     pub fn borrow(&'a self) -> PlayerOptions<'a> {
         PlayerOptions {
-            tracks: self.tracks,
-            delay_mul: self.delay_mul
+            tracks: Cow::Borrowed(&self.tracks),
+            transpose: self.transpose,
+            delay_mul: self.delay_mul,
+            out_of_range: self.out_of_range,
+            priorities: Cow::Borrowed(&self.priorities),
+            reserved: Cow::Borrowed(&self.reserved),
+            shared_fallback: self.shared_fallback,
+            arpeggio: self.arpeggio,
+            start: self.start,
+            looped: self.looped
         }
     }
+
+    /// Replace the transposition of each track by the octave shift that puts the most
+    /// of its notes in the range supported by the buzzers
+    ///
+    /// The remaining notes are handled according to `out_of_range`
+    pub fn fit_range(mut self, song: &Song) -> PlayerOptions<'a> {
+        let tracks = self.tracks.iter()
+            .map(|&(i, _)| (i, song.tracks.get(i).map_or(0, |t| t.best_octave_shift(self.transpose as i16))))
+            .collect();
+
+        self.tracks = Cow::Owned(tracks);
+        self
+    }
 }
 
 /// Arduplayer's main interface to play songs and notes
-pub struct Player {
-    port: Box<SerialPort>,
-    scheduler: NoteScheduler
+///
+/// By default, notes are played on an Arduino connected through the serial port, but any
+/// `Backend` can be used instead
+pub struct Player<B: Backend = SerialBackend> {
+    backend: B,
+    scheduler: NoteScheduler,
+    /// Whether the buzzers are silenced while notes keep being tracked, see `mute`
+    muted: bool
 }
 
-impl Player {
-    /// Create a new `Player` with the given number of buzzers
+impl Player<SerialBackend> {
+    /// Create a new `Player` with the given number of buzzers, using the serial port
+    ///
+    /// When all buzzers are busy, new notes are dropped
     pub fn new(buzzers: u8) -> Result<Player, serialport::Error> {
-        let port_name = serial::get_port_name();
-        let port = serial::open_port(&port_name)?;
-        let scheduler = NoteScheduler::new(buzzers);
+        Player::with_allocator(buzzers, VoicePolicy::DropNew)
+    }
 
-        Ok(Player { port, scheduler })
+    /// Create a new `Player` with the given number of buzzers, using the serial port,
+    /// which are assigned to notes according to `allocator` (usually one of the
+    /// `VoicePolicy` variants)
+    pub fn with_allocator<A: VoiceAllocator + Send + 'static>(buzzers: u8, allocator: A) -> Result<Player, serialport::Error> {
+        Ok(Player::with_backend(SerialBackend::open()?, buzzers, allocator))
     }
+}
 
-    /// Play the `Song` using the provided `PlayerOptions`
-    pub fn play_song(&mut self, song: Song, options: PlayerOptions) {
+impl<B: Backend> Player<B> {
+    /// Create a new `Player` that plays on `backend`, with the given number of buzzers
+    /// which are assigned to notes according to `allocator`
+    pub fn with_backend<A: VoiceAllocator + Send + 'static>(backend: B, buzzers: u8, allocator: A) -> Player<B> {
+        let scheduler = NoteScheduler::new(buzzers, Box::new(allocator));
+        Player { backend, scheduler, muted: false }
+    }
+
+    /// Return a reference to the backend
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    /// Return the backend, consuming the player
+    pub fn into_backend(self) -> B {
+        self.backend
+    }
+
+    /// Play the `Song` using the provided `PlayerOptions`, and return how well the
+    /// timing was kept
+    ///
+    /// This blocks until the song ends, see `spawn` to play in the background
+    pub fn play_song(&mut self, song: Song, options: PlayerOptions) -> PlaybackReport {
+        let report = self.prepare(&song, &options, None).run(self);
+
+        self.finish();
+        report
+    }
+
+    /// Set up the scheduler for the options and return the playback of the song, which
+    /// can be controlled through `controls` if given
+    pub(crate) fn prepare(&mut self, song: &Song, options: &PlayerOptions, controls: Option<Receiver<Control>>) -> Playback {
         // Filter out track numbers not mentioned in the options (useful to get
         // rid of tracks that are too noisy or useless ones like drums)
         let keep = |id| options.tracks.iter().find(|&&(track_id, _)| id == track_id);
-        let tracks: Vec<_> = song.tracks.into_iter().enumerate()
+        let tracks: Vec<_> = song.tracks.iter().enumerate()
             // Keep only the tracks that are mentioned in the options
-            .filter_map(|(i, track)| (keep)(i).map(|&(_, transpose)| (track, transpose)))
-            // Transpose them
-            .map(|(track, transpose)| track.transpose(transpose))
+            .filter(|&(i, _)| (keep)(i).is_some())
+            .map(|(i, track)| {
+                // The events may have been stamped with another index if the tracks of the
+                // song were edited, and the options refer to tracks by their current index
+                let mut track = track.clone();
+                track.set_index(i);
+                track
+            })
             .collect();
 
+        // The notes are transposed as they are played, since the transposition can change
+        // while the song plays
         let track = song::merge_tracks(tracks);
+        self.scheduler.set_priorities(&options.priorities);
+        self.scheduler.set_reserved(&options.reserved, options.shared_fallback);
+        // An arpeggio without period would never let the song move on
+        let arpeggio = options.arpeggio.filter(|a| a.period != Duration::from_secs(0));
+        self.scheduler.set_arpeggio(arpeggio.map(|a| a.buzzers));
 
-        for event in track.events() {
-            match *event {
-                Event::Play { tone, .. } => self.play_note(tone, true),
-                Event::Stop { tone } => self.play_note(tone, false),
-                Event::Wait(time) => {
-                    if time != 0 {
-                        thread::sleep(Duration::from_millis((time as f64 * options.delay_mul) as u64));
-                    }
-                }
-            }
+        Playback::new(&track, song, options, arpeggio.map(|a| a.period), controls)
+    }
+
+    /// Stop the notes left by a song and disable its arpeggio
+    pub(crate) fn finish(&mut self) {
+        self.scheduler.set_arpeggio(None);
+        self.muted = false;
+        self.silence();
+    }
+
+    /// Return the current instant of the backend's clock
+    pub(crate) fn now(&self) -> Instant {
+        self.backend.now()
+    }
+
+    /// Return whether the backend plays in real time, see `Backend::is_realtime`
+    pub(crate) fn is_realtime(&self) -> bool {
+        self.backend.is_realtime()
+    }
+
+    /// Let the backend wait for the given time
+    pub(crate) fn wait(&mut self, duration: Duration) {
+        self.backend.wait(duration);
+    }
+
+    /// Stop all notes
+    pub fn silence(&mut self) {
+        self.scheduler.stop_all();
+        self.backend.silence_all().expect("Something went wrong");
+        self.backend.flush().expect("Something went wrong");
+    }
+
+    /// Stop the buzzers, but keep track of the notes that are playing
+    ///
+    /// Until `unmute` is called, notes are started and stopped without sending anything
+    /// to the backend
+    pub(crate) fn mute(&mut self) {
+        self.muted = true;
+        self.backend.silence_all().expect("Something went wrong");
+        self.backend.flush().expect("Something went wrong");
+    }
+
+    /// Play the notes that are playing according to the scheduler, after muting the buzzers
+    pub(crate) fn unmute(&mut self) {
+        self.muted = false;
+        let notes: Vec<_> = self.scheduler.assigned().map(|(buzzer_id, voice)| (buzzer_id, voice.note)).collect();
+        for (buzzer_id, midi_code) in notes {
+            let freq = util::midi_code_to_freq(midi_code).unwrap_or(0);
+            self.set_frequency(buzzer_id, freq);
+        }
+    }
+
+    /// Send the frequency of a buzzer to the backend, unless the player is muted
+    fn set_frequency(&mut self, buzzer_id: u8, freq: u16) {
+        if !self.muted {
+            self.backend.set_frequency(buzzer_id, freq).expect("Something went wrong");
+        }
+    }
+
+    /// Play the next step of the arpeggio
+    pub(crate) fn arpeggiate(&mut self) {
+        for (buzzer_id, midi_code) in self.scheduler.arpeggiate() {
+            // The scheduler only gets notes in range, so there is always a frequency
+            let freq = util::midi_code_to_freq(midi_code).unwrap_or(0);
+            self.set_frequency(buzzer_id, freq);
         }
     }
 
     /// Play (or stop playing) a single note
+    ///
+    /// Notes outside of the range supported by the buzzers are ignored
     pub fn play_note(&mut self, midi_code: u8, on: bool) {
-        // Get available buzzer, if any
-        let maybe_buzzer_id = if on {
-            self.scheduler.start_note(midi_code)
+        if on {
+            self.start_note(midi_code, 127, 0, 0);
         } else {
-            self.scheduler.stop_note(midi_code)
+            self.stop_note(midi_code, 0, 0);
+        }
+    }
+
+    /// Start playing a single note, with the given velocity, channel and track
+    ///
+    /// The velocity, the channel and the track are only used by the voice allocator,
+    /// to decide which note to drop when all buzzers are busy
+    pub fn start_note(&mut self, midi_code: u8, velocity: u8, channel: u8, track: usize) {
+        self.start_tone(midi_code, midi_code, velocity, channel, track);
+    }
+
+    /// Start playing `midi_code` for the given tone of a song, which is what identifies
+    /// the note when stopping or retuning it
+    pub(crate) fn start_tone(&mut self, tone: u8, midi_code: u8, velocity: u8, channel: u8, track: usize) {
+        let freq = match util::midi_code_to_freq(midi_code) {
+            Some(freq) => freq,
+            None => return
         };
 
-        if let Some(buzzer_id) = maybe_buzzer_id {
-            let freq = if on {
-                util::midi_code_to_freq(midi_code).unwrap()
-            } else {
-                0
-            };
+        // Get available buzzer, if any
+        if let Some(buzzer_id) = self.scheduler.start_tone(tone, midi_code, velocity, channel, track) {
+            self.set_frequency(buzzer_id, freq);
+        }
+    }
+
+    /// Change the pitch of a tone that is playing, without changing its buzzer
+    pub(crate) fn retune(&mut self, tone: u8, channel: u8, track: usize, new_midi_code: u8) {
+        if let Some(buzzer_id) = self.scheduler.retune(tone, channel, track, new_midi_code) {
+            let freq = util::midi_code_to_freq(new_midi_code).unwrap_or(0);
+            self.set_frequency(buzzer_id, freq);
+        }
+    }
 
-            serial::write_note(&mut *self.port, buzzer_id, freq).expect("Something went wrong");
+    /// Stop playing a single note, which was started with the given channel and track
+    pub fn stop_note(&mut self, midi_code: u8, channel: u8, track: usize) {
+        if let Some(buzzer_id) = self.scheduler.stop_note(midi_code, channel, track) {
+            self.set_frequency(buzzer_id, 0);
         }
     }
 
+    /// Return the amount of buzzers this player can use
+    pub fn buzzers(&self) -> u8 {
+        self.scheduler.buzzer_count()
+    }
+
     pub fn playing<'a>(&'a self) -> impl Iterator<Item=u8> + 'a {
-        self.scheduler.playing.iter().map(|(note, _)| *note)
+        self.scheduler.playing().map(|voice| voice.note)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PlayerOptions;
+    use song::{Event, Song, Track};
+    use tempo::{TempoMap, TimeBase};
+
+    fn song(tracks: &[&[u8]]) -> Song {
+        let tracks = tracks.iter().map(|tones| Track::new(tones.iter().flat_map(|&tone| vec![
+            Event::Play { tone, velocity: 100, channel: 0, track: 0 },
+            Event::Wait(1000),
+            Event::Stop { tone, channel: 0, track: 0 }
+        ]).collect())).collect();
+
+        Song { time_base: TimeBase::TicksPerQuarter(96), tempo_map: TempoMap::default(), time_signatures: Vec::new(), tracks, warnings: Vec::new() }
+    }
+
+    #[test]
+    fn fit_range() {
+        let song = song(&[&[60, 64], &[12, 14], &[110, 112]]);
+
+        // The hand-picked octaves are replaced, and unknown tracks are left alone
+        let options = PlayerOptions { tracks: vec![(0, 2), (2, 0), (1, -1), (7, 1)].into(), ..PlayerOptions::default() };
+        assert_eq!(&*options.fit_range(&song).tracks, &[(0, 0), (2, -1), (1, 1), (7, 0)]);
+
+        // Four octaves up, the first track has to go down
+        let options = PlayerOptions { tracks: vec![(0, 0), (1, 0)].into(), transpose: 48, ..PlayerOptions::default() };
+        assert_eq!(&*options.fit_range(&song).tracks, &[(0, -1), (1, 0)]);
     }
 }
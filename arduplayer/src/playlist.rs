@@ -0,0 +1,612 @@
+//! A queue of songs to be played one after another
+
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use rand::{self, Rng};
+
+use backend::Backend;
+use playback::Control;
+use player::{Player, PlayerOptions};
+use song::Song;
+
+/// What to do when a song of the playlist ends
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Repeat {
+    /// Play the next song, and stop after the last one
+    Off,
+    /// Play the same song again
+    One,
+    /// Play the next song, and start over after the last one
+    All
+}
+
+/// A list of songs, along with the options to play each of them
+pub struct Playlist {
+    /// The songs along with their options, shared with the thread playing them if any
+    entries: Vec<Arc<(Song, PlayerOptions<'static>)>>,
+    /// Indices of the entries, in the order they are played
+    order: Vec<usize>,
+    /// Position in `order` of the song being played, if any
+    current: Option<usize>,
+    shuffle: bool,
+    pub repeat: Repeat,
+    /// Silence between songs (zero for gapless playback)
+    pub gap: Duration
+}
+
+/// A request to move within the playlist, sent from a `PlaylistHandle`
+#[derive(Copy, Clone)]
+enum Request {
+    Skip,
+    Previous,
+    Stop
+}
+
+impl Playlist {
+    /// Create an empty playlist, without shuffle, repeat or gaps
+    pub fn new() -> Playlist {
+        Playlist {
+            entries: Vec::new(),
+            order: Vec::new(),
+            current: None,
+            shuffle: false,
+            repeat: Repeat::Off,
+            gap: Duration::from_secs(0)
+        }
+    }
+
+    /// Add a song at the end of the playlist
+    ///
+    /// When shuffling, the song is placed at a random position among the ones that have
+    /// not been played yet
+    pub fn push(&mut self, song: Song, options: PlayerOptions<'static>) {
+        let index = self.entries.len();
+        self.entries.push(Arc::new((song, options)));
+
+        let first = self.current.map_or(0, |c| c + 1);
+        let position = if self.shuffle {
+            rand::thread_rng().gen_range(first, self.order.len() + 1)
+        } else {
+            self.order.len()
+        };
+
+        self.order.insert(position, index);
+    }
+
+    /// Return the amount of songs in the playlist
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Return the songs in the order they will be played
+    pub fn songs(&self) -> impl Iterator<Item=&Song> {
+        self.order.iter().map(move |&i| &self.entries[i].0)
+    }
+
+    /// Return the position of the current song in `songs`, if any
+    pub fn current(&self) -> Option<usize> {
+        self.current
+    }
+
+    /// Enable or disable shuffling
+    ///
+    /// Enabling it shuffles the songs that have not been played yet, and disabling it
+    /// restores the original order, keeping the current song
+    pub fn set_shuffle(&mut self, shuffle: bool) {
+        self.shuffle = shuffle;
+        if shuffle {
+            let first = self.current.map_or(0, |c| c + 1);
+            rand::thread_rng().shuffle(&mut self.order[first..]);
+        } else {
+            let playing = self.current.map(|c| self.order[c]);
+            self.order.sort();
+            self.current = playing;
+        }
+    }
+
+    /// Return whether shuffling is enabled
+    pub fn shuffle(&self) -> bool {
+        self.shuffle
+    }
+
+    /// Move to the song that follows the current one when it ends, according to `repeat`,
+    /// and return its position
+    pub fn advance(&mut self) -> Option<usize> {
+        self.current = match (self.current, self.repeat) {
+            (Some(current), Repeat::One) => Some(current),
+            _ => self.following()
+        };
+
+        self.current
+    }
+
+    /// Move to the next song, regardless of `Repeat::One`, and return its position
+    pub fn skip(&mut self) -> Option<usize> {
+        self.current = self.following();
+        self.current
+    }
+
+    /// Move to the previous song and return its position
+    ///
+    /// Going back from the first song starts it over, unless repeating all songs
+    pub fn previous(&mut self) -> Option<usize> {
+        self.current = match self.current {
+            Some(0) | None if self.repeat == Repeat::All && !self.order.is_empty() => Some(self.order.len() - 1),
+            Some(current) => Some(current.saturating_sub(1)),
+            None => None
+        };
+
+        self.current
+    }
+
+    /// Return the position after the current one, starting over after the last song
+    /// only when repeating all songs
+    fn following(&mut self) -> Option<usize> {
+        let next = self.current.map_or(0, |c| c + 1);
+        if next < self.order.len() {
+            return Some(next);
+        }
+
+        if self.repeat == Repeat::All && !self.order.is_empty() {
+            // Every round gets a new order, which must not start with the song that ended
+            // the previous one
+            if self.shuffle {
+                let last = self.order[self.order.len() - 1];
+                let mut rng = rand::thread_rng();
+                rng.shuffle(&mut self.order);
+                if self.order.len() > 1 && self.order[0] == last {
+                    let other = rng.gen_range(1, self.order.len());
+                    self.order.swap(0, other);
+                }
+            }
+
+            Some(0)
+        } else {
+            None
+        }
+    }
+
+    /// Play the songs one after another, blocking until the playlist ends
+    ///
+    /// Note: with `Repeat::One` or `Repeat::All`, this never returns
+    pub fn play<B: Backend>(&mut self, player: &mut Player<B>) {
+        let mut position = self.advance();
+        while let Some(current) = position {
+            self.play_song(player, current, None);
+
+            position = self.advance();
+            if position.is_some() {
+                player.wait(self.gap);
+            }
+        }
+    }
+
+    /// Play the songs on a background thread, returning a handle to control them
+    ///
+    /// The playlist can still be changed through the handle while it plays
+    pub fn spawn<B: Backend + Send + 'static>(mut self, mut player: Player<B>) -> PlaylistHandle<B> {
+        self.advance();
+        let shared = Shared { playlist: self, song: None, request: None, paused: false };
+        let state = Arc::new(State { shared: Mutex::new(shared), changed: Condvar::new() });
+
+        let thread_state = state.clone();
+        let thread = thread::spawn(move || {
+            let state = thread_state;
+            loop {
+                let mut shared = state.lock();
+
+                // Songs don't start while the playlist is paused
+                while shared.paused && shared.request.is_none() {
+                    shared = state.changed.wait(shared).unwrap();
+                }
+
+                // Requests made between two songs apply to the upcoming one
+                if let Some(request) = shared.request.take() {
+                    shared.playlist.navigate(request);
+                    continue;
+                }
+
+                // The position is read again for every song, since the handle may have
+                // reordered the playlist in the meantime
+                let current = match shared.playlist.current() {
+                    Some(current) => current,
+                    None => break
+                };
+
+                let (controls, receiver) = mpsc::channel();
+                shared.song = Some(controls);
+                let entry = shared.playlist.entry(current);
+                drop(shared);
+
+                play_entry(&mut player, &entry, Some(receiver));
+
+                let mut shared = state.lock();
+                shared.song = None;
+                let next = match shared.request.take() {
+                    Some(request) => shared.playlist.navigate(request),
+                    None => shared.playlist.advance()
+                };
+
+                if next.is_some() {
+                    let gap = shared.playlist.gap;
+                    drop(shared);
+                    state.wait_gap(&mut player, gap);
+                }
+            }
+
+            player
+        });
+
+        PlaylistHandle { state, thread }
+    }
+
+    /// Move within the playlist as requested and return the new position
+    fn navigate(&mut self, request: Request) -> Option<usize> {
+        match request {
+            Request::Skip => self.skip(),
+            Request::Previous => self.previous(),
+            Request::Stop => {
+                self.current = None;
+                None
+            }
+        }
+    }
+
+    /// Return the song at the given position of the playlist, along with its options
+    fn entry(&self, position: usize) -> Arc<(Song, PlayerOptions<'static>)> {
+        self.entries[self.order[position]].clone()
+    }
+
+    /// Play the song at the given position of the playlist
+    fn play_song<B: Backend>(&self, player: &mut Player<B>, position: usize, controls: Option<Receiver<Control>>) {
+        play_entry(player, &self.entry(position), controls);
+    }
+}
+
+/// Play a song of a playlist, with the given controls if it is played in the background
+fn play_entry<B: Backend>(player: &mut Player<B>, entry: &(Song, PlayerOptions), controls: Option<Receiver<Control>>) {
+    let (ref song, ref options) = *entry;
+    player.prepare(song, options, controls).run(player);
+    player.finish();
+}
+
+impl Default for Playlist {
+    fn default() -> Playlist {
+        Playlist::new()
+    }
+}
+
+/// State shared between a playlist thread and its handle
+struct State {
+    shared: Mutex<Shared>,
+    /// Notified when a request is made or the playlist is resumed
+    changed: Condvar
+}
+
+struct Shared {
+    playlist: Playlist,
+    /// Controls of the song being played
+    song: Option<Sender<Control>>,
+    /// A pending request from the handle
+    request: Option<Request>,
+    paused: bool
+}
+
+impl State {
+    fn lock(&self) -> MutexGuard<'_, Shared> {
+        self.shared.lock().unwrap()
+    }
+
+    /// Wait for the gap between two songs, or until a request is made
+    fn wait_gap<B: Backend>(&self, player: &mut Player<B>, gap: Duration) {
+        // Backends with their own clock don't take real time to wait
+        if !player.is_realtime() {
+            player.wait(gap);
+            return;
+        }
+
+        let end = Instant::now() + gap;
+        let mut shared = self.lock();
+        while shared.request.is_none() {
+            let now = Instant::now();
+            if now >= end {
+                break;
+            }
+
+            shared = self.changed.wait_timeout(shared, end - now).unwrap().0;
+        }
+    }
+}
+
+/// A handle to control a playlist playing on a background thread, see `Playlist::spawn`
+pub struct PlaylistHandle<B: Backend> {
+    state: Arc<State>,
+    thread: JoinHandle<Player<B>>
+}
+
+impl<B: Backend> PlaylistHandle<B> {
+    /// Stop the current song and play the next one
+    pub fn skip(&self) {
+        self.request(Request::Skip);
+    }
+
+    /// Stop the current song and play the previous one
+    pub fn previous(&self) {
+        self.request(Request::Previous);
+    }
+
+    /// Stop the playlist
+    pub fn stop(&self) {
+        self.request(Request::Stop);
+    }
+
+    /// Pause the current song, or keep the next one from starting if the playlist is
+    /// between two songs
+    pub fn pause(&self) {
+        let mut shared = self.state.lock();
+        shared.paused = true;
+        shared.control(Control::Pause);
+    }
+
+    /// Resume the playlist after pausing it
+    pub fn resume(&self) {
+        let mut shared = self.state.lock();
+        shared.paused = false;
+        shared.control(Control::Resume);
+        self.state.changed.notify_all();
+    }
+
+    /// Add a song at the end of the playlist, see `Playlist::push`
+    pub fn push(&self, song: Song, options: PlayerOptions<'static>) {
+        self.state.lock().playlist.push(song, options);
+    }
+
+    /// Enable or disable shuffling, see `Playlist::set_shuffle`
+    pub fn set_shuffle(&self, shuffle: bool) {
+        self.state.lock().playlist.set_shuffle(shuffle);
+    }
+
+    /// Change what to do when a song ends
+    pub fn set_repeat(&self, repeat: Repeat) {
+        self.state.lock().playlist.repeat = repeat;
+    }
+
+    /// Change the silence between songs
+    pub fn set_gap(&self, gap: Duration) {
+        self.state.lock().playlist.gap = gap;
+    }
+
+    /// Return the position of the song being played (or about to be played) in
+    /// `Playlist::songs`, if any
+    pub fn position(&self) -> Option<usize> {
+        self.state.lock().playlist.current()
+    }
+
+    /// Return whether the playlist has ended or has been stopped
+    pub fn is_finished(&self) -> bool {
+        self.state.lock().playlist.current().is_none()
+    }
+
+    /// Block until the playlist ends or is stopped, and return it along with the player
+    pub fn wait(self) -> (Playlist, Player<B>) {
+        let player = self.thread.join().expect("The playlist thread panicked");
+
+        // The thread has ended, so nobody else holds the state
+        let state = Arc::try_unwrap(self.state).ok().expect("The playlist state is still shared");
+        (state.shared.into_inner().unwrap().playlist, player)
+    }
+
+    /// Register the request and stop the current song, so the playlist thread handles it
+    fn request(&self, request: Request) {
+        let mut shared = self.state.lock();
+        shared.request = Some(request);
+        shared.control(Control::Stop);
+        self.state.changed.notify_all();
+    }
+}
+
+impl Shared {
+    fn control(&self, control: Control) {
+        if let Some(ref song) = self.song {
+            // The song may have just finished, in which case there is nothing to control
+            let _ = song.send(control);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use super::{Playlist, Repeat};
+    use backend::Backend;
+    use player::{Player, PlayerOptions};
+    use recording::RecordingBackend;
+    use song::Song;
+    use voice::VoicePolicy;
+
+    /// A single track at 96 ticks per quarter playing C4 for 500 ms
+    const NOTE: &[u8] = &[
+        b'M', b'T', b'h', b'd', 0, 0, 0, 6, 0, 0, 0, 1, 0, 96,
+        b'M', b'T', b'r', b'k', 0, 0, 0, 12,
+        0x00, 0x90, 60, 100,
+        0x60, 0x80, 60, 64,
+        0x00, 0xFF, 0x2F, 0x00
+    ];
+
+    /// A single track without any note
+    const EMPTY: &[u8] = &[
+        b'M', b'T', b'h', b'd', 0, 0, 0, 6, 0, 0, 0, 1, 0, 96,
+        b'M', b'T', b'r', b'k', 0, 0, 0, 4,
+        0x00, 0xFF, 0x2F, 0x00
+    ];
+
+    /// A backend that plays in real time without sending anything
+    struct SilentBackend;
+
+    impl Backend for SilentBackend {
+        fn set_frequency(&mut self, _buzzer: u8, _freq: u16) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn silence_all(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn options() -> PlayerOptions<'static> {
+        PlayerOptions { tracks: vec![(0, 0)].into(), ..PlayerOptions::default() }
+    }
+
+    fn playlist(songs: usize, bytes: &[u8]) -> Playlist {
+        let mut playlist = Playlist::new();
+        for _ in 0..songs {
+            playlist.push(Song::from_bytes(bytes).unwrap(), options());
+        }
+
+        playlist
+    }
+
+    /// Return the indices of the songs in the order they are played
+    fn order(playlist: &Playlist) -> Vec<usize> {
+        playlist.order.clone()
+    }
+
+    #[test]
+    fn advance() {
+        let mut playlist = playlist(3, NOTE);
+        assert_eq!(playlist.current(), None);
+        assert_eq!(playlist.advance(), Some(0));
+        assert_eq!(playlist.advance(), Some(1));
+        assert_eq!(playlist.advance(), Some(2));
+        assert_eq!(playlist.advance(), None);
+
+        playlist.repeat = Repeat::One;
+        assert_eq!(playlist.advance(), Some(0));
+        assert_eq!(playlist.advance(), Some(0));
+        assert_eq!(playlist.skip(), Some(1));
+        assert_eq!(playlist.advance(), Some(1));
+
+        playlist.repeat = Repeat::All;
+        assert_eq!(playlist.advance(), Some(2));
+        assert_eq!(playlist.advance(), Some(0));
+        assert_eq!(playlist.previous(), Some(2));
+        assert_eq!(playlist.previous(), Some(1));
+
+        playlist.repeat = Repeat::Off;
+        assert_eq!(playlist.previous(), Some(0));
+        assert_eq!(playlist.previous(), Some(0));
+        assert_eq!(playlist.skip(), Some(1));
+    }
+
+    #[test]
+    fn empty() {
+        let mut playlist = Playlist::new();
+        playlist.repeat = Repeat::All;
+        assert_eq!(playlist.advance(), None);
+        assert_eq!(playlist.skip(), None);
+        assert_eq!(playlist.previous(), None);
+    }
+
+    #[test]
+    fn shuffle() {
+        let mut playlist = playlist(20, NOTE);
+        playlist.advance();
+        playlist.advance();
+        let played = order(&playlist)[..2].to_vec();
+
+        // Only the songs that have not been played yet are shuffled
+        playlist.set_shuffle(true);
+        let mut shuffled = order(&playlist);
+        assert_eq!(&shuffled[..2], &played[..]);
+        shuffled.sort();
+        assert_eq!(shuffled, (0..20).collect::<Vec<_>>());
+
+        // Songs added while shuffling are not played before the current one
+        playlist.push(Song::from_bytes(NOTE).unwrap(), options());
+        let position = order(&playlist).iter().position(|&i| i == 20).unwrap();
+        assert!(position > 1);
+
+        // Disabling shuffling keeps the current song
+        let current = order(&playlist)[1];
+        playlist.set_shuffle(false);
+        assert_eq!(order(&playlist), (0..21).collect::<Vec<_>>());
+        assert_eq!(playlist.current(), Some(current));
+    }
+
+    #[test]
+    fn reshuffle() {
+        // Starting a new round never plays the last song twice in a row
+        let mut playlist = playlist(2, NOTE);
+        playlist.repeat = Repeat::All;
+        playlist.set_shuffle(true);
+
+        let mut last = None;
+        for _ in 0..100 {
+            let current = playlist.advance().unwrap();
+            let song = order(&playlist)[current];
+            assert_ne!(Some(song), last);
+            last = Some(song);
+        }
+    }
+
+    #[test]
+    fn spawn() {
+        let mut playlist = playlist(2, NOTE);
+        playlist.gap = Duration::from_millis(250);
+
+        let player = Player::with_backend(RecordingBackend::new(), 1, VoicePolicy::DropNew);
+        let handle = playlist.spawn(player);
+        handle.push(Song::from_bytes(EMPTY).unwrap(), options());
+        let (playlist, player) = handle.wait();
+
+        assert_eq!(playlist.current(), None);
+        assert_eq!(playlist.len(), 3);
+        let starts: Vec<_> = player.into_backend().commands().iter()
+            .filter(|c| c.freq != 0)
+            .map(|c| c.time)
+            .collect();
+        assert_eq!(starts, vec![Duration::from_millis(0), Duration::from_millis(750)]);
+    }
+
+    #[test]
+    fn stop_during_gap() {
+        let mut playlist = playlist(2, EMPTY);
+        playlist.gap = Duration::from_secs(60);
+
+        let start = Instant::now();
+        let handle = playlist.spawn(Player::with_backend(SilentBackend, 1, VoicePolicy::DropNew));
+        thread::sleep(Duration::from_millis(50));
+        handle.stop();
+        handle.wait();
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn pause_during_gap() {
+        let mut playlist = playlist(2, EMPTY);
+        playlist.gap = Duration::from_millis(50);
+
+        let handle = playlist.spawn(Player::with_backend(SilentBackend, 1, VoicePolicy::DropNew));
+        handle.pause();
+        thread::sleep(Duration::from_millis(300));
+        assert!(!handle.is_finished());
+
+        handle.resume();
+        let (playlist, _) = handle.wait();
+        assert_eq!(playlist.current(), None);
+    }
+}
@@ -0,0 +1,359 @@
+//! A backend that records the commands sent to the buzzers, to test playback
+
+use std::io;
+use std::time::{Duration, Instant};
+
+use backend::Backend;
+
+/// A command that would be sent to the Arduino
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Command {
+    /// Time since the start of the recording
+    pub time: Duration,
+    pub buzzer: u8,
+    /// The frequency to play, or 0 to stop the buzzer
+    pub freq: u16
+}
+
+/// A backend that records every command instead of playing it
+///
+/// Waiting only advances a virtual clock, so the recording is fast and deterministic
+#[derive(Clone, Debug)]
+pub struct RecordingBackend {
+    /// The instant the virtual clock started at
+    start: Instant,
+    clock: Duration,
+    commands: Vec<Command>,
+    /// Buzzers that are currently playing a note
+    sounding: Vec<u8>
+}
+
+impl RecordingBackend {
+    pub fn new() -> RecordingBackend {
+        RecordingBackend { start: Instant::now(), clock: Duration::from_secs(0), commands: Vec::new(), sounding: Vec::new() }
+    }
+
+    /// Return the commands recorded so far
+    pub fn commands(&self) -> &[Command] {
+        &self.commands
+    }
+
+    /// Return the time elapsed on the virtual clock
+    pub fn elapsed(&self) -> Duration {
+        self.clock
+    }
+}
+
+impl Default for RecordingBackend {
+    fn default() -> RecordingBackend {
+        RecordingBackend::new()
+    }
+}
+
+impl Backend for RecordingBackend {
+    fn set_frequency(&mut self, buzzer: u8, freq: u16) -> io::Result<()> {
+        self.sounding.retain(|&b| b != buzzer);
+        if freq != 0 {
+            self.sounding.push(buzzer);
+        }
+
+        self.commands.push(Command { time: self.clock, buzzer, freq });
+        Ok(())
+    }
+
+    fn silence_all(&mut self) -> io::Result<()> {
+        // Record the same commands as the serial backend
+        for buzzer in self.sounding.drain(..) {
+            self.commands.push(Command { time: self.clock, buzzer, freq: 0 });
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn wait(&mut self, duration: Duration) {
+        self.clock += duration;
+    }
+
+    fn now(&self) -> Instant {
+        self.start + self.clock
+    }
+
+    fn is_realtime(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    use super::{Command, RecordingBackend};
+    use playback::Control;
+    use player::{Loop, Player, PlayerOptions, Position};
+    use song::Song;
+    use tempo::TimeSignature;
+    use util::OutOfRange;
+    use voice::VoicePolicy;
+
+    /// A single track at 96 ticks per quarter: C4 and E4 together, then G4
+    const CHORD: &[u8] = &[
+        b'M', b'T', b'h', b'd', 0, 0, 0, 6, 0, 0, 0, 1, 0, 96,
+        b'M', b'T', b'r', b'k', 0, 0, 0, 28,
+        0x00, 0x90, 60, 100,
+        0x00, 0x90, 64, 100,
+        0x60, 0x80, 60, 64,
+        0x00, 0x80, 64, 64,
+        0x00, 0x90, 67, 100,
+        0x60, 0x80, 67, 64,
+        0x00, 0xFF, 0x2F, 0x00
+    ];
+
+    fn command(millis: u64, buzzer: u8, freq: u16) -> Command {
+        Command { time: Duration::from_millis(millis), buzzer, freq }
+    }
+
+    fn record(buzzers: u8, options: PlayerOptions) -> RecordingBackend {
+        let song = Song::from_bytes(CHORD).unwrap();
+        let mut player = Player::with_backend(RecordingBackend::new(), buzzers, VoicePolicy::DropNew);
+        player.play_song(song, options);
+        player.into_backend()
+    }
+
+    /// Play the song with the given controls already sent, so they are handled as soon as
+    /// the playback waits for the first time
+    fn record_with_controls(buzzers: u8, options: PlayerOptions, controls: Vec<Control>) -> RecordingBackend {
+        let (sender, receiver) = mpsc::channel();
+        for control in controls {
+            sender.send(control).unwrap();
+        }
+
+        let song = Song::from_bytes(CHORD).unwrap();
+        let mut player = Player::with_backend(RecordingBackend::new(), buzzers, VoicePolicy::DropNew);
+        player.prepare(&song, &options, Some(receiver)).run(&mut player);
+        player.finish();
+        player.into_backend()
+    }
+
+    #[test]
+    fn chord() {
+        let options = PlayerOptions { tracks: vec![(0, 0)].into(), ..PlayerOptions::default() };
+        let backend = record(2, options);
+
+        assert_eq!(backend.commands(), &[
+            command(0, 0, 262),
+            command(0, 1, 330),
+            command(500, 0, 0),
+            command(500, 1, 0),
+            command(500, 0, 392),
+            command(1000, 0, 0)
+        ]);
+        assert_eq!(backend.elapsed(), Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn start_with_held_notes() {
+        let options = PlayerOptions {
+            tracks: vec![(0, 0)].into(),
+            start: Position::Time(Duration::from_millis(250)),
+            ..PlayerOptions::default()
+        };
+        let backend = record(2, options);
+
+        assert_eq!(backend.commands(), &[
+            command(0, 0, 262),
+            command(0, 1, 330),
+            command(250, 0, 0),
+            command(250, 1, 0),
+            command(250, 0, 392),
+            command(750, 0, 0)
+        ]);
+    }
+
+    #[test]
+    fn looped_region() {
+        let region = Loop { start: Position::Time(Duration::from_secs(0)), end: Position::Time(Duration::from_millis(500)), count: Some(2) };
+        let options = PlayerOptions { tracks: vec![(0, 0)].into(), looped: Some(region), ..PlayerOptions::default() };
+        let backend = record(2, options);
+
+        assert_eq!(backend.commands(), &[
+            command(0, 0, 262),
+            command(0, 1, 330),
+            command(500, 0, 0),
+            command(500, 1, 0),
+            command(500, 0, 262),
+            command(500, 1, 330),
+            command(1000, 0, 0),
+            command(1000, 1, 0),
+            command(1000, 0, 392),
+            command(1500, 0, 0)
+        ]);
+    }
+
+    #[test]
+    fn transpose_with_track_octaves() {
+        // One octave up, then a fourth down: C4 E4 G4 become G4 B4 D5
+        let options = PlayerOptions { tracks: vec![(0, 1)].into(), transpose: -5, ..PlayerOptions::default() };
+        let backend = record(2, options);
+
+        assert_eq!(backend.commands(), &[
+            command(0, 0, 392),
+            command(0, 1, 494),
+            command(500, 0, 0),
+            command(500, 1, 0),
+            command(500, 0, 587),
+            command(1000, 0, 0)
+        ]);
+    }
+
+    #[test]
+    fn transpose_out_of_range() {
+        // Three octaves and a fifth up, G4 goes past B7
+        let options = PlayerOptions { tracks: vec![(0, 3)].into(), transpose: 7, out_of_range: OutOfRange::Drop, ..PlayerOptions::default() };
+        let backend = record(2, options);
+
+        assert_eq!(backend.commands(), &[
+            command(0, 0, 3136),
+            command(0, 1, 3951),
+            command(500, 0, 0),
+            command(500, 1, 0)
+        ]);
+    }
+
+    #[test]
+    fn notes_folded_into_the_same_pitch() {
+        // C7 and C8 are held together, and C8 folds into C7, but stops first
+        let bytes = [
+            b'M', b'T', b'h', b'd', 0, 0, 0, 6, 0, 0, 0, 1, 0, 96,
+            b'M', b'T', b'r', b'k', 0, 0, 0, 20,
+            0x00, 0x90, 96, 100,
+            0x00, 0x90, 108, 100,
+            0x30, 0x80, 108, 64,
+            0x30, 0x80, 96, 64,
+            0x00, 0xFF, 0x2F, 0x00
+        ];
+
+        let song = Song::from_bytes(&bytes).unwrap();
+        let mut player = Player::with_backend(RecordingBackend::new(), 2, VoicePolicy::DropNew);
+        player.play_song(song, PlayerOptions { tracks: vec![(0, 0)].into(), ..PlayerOptions::default() });
+
+        assert_eq!(player.into_backend().commands(), &[
+            command(0, 0, 2093),
+            command(0, 1, 2093),
+            command(250, 1, 0),
+            command(500, 0, 0)
+        ]);
+    }
+
+    #[test]
+    fn live_transpose_restores_dropped_notes() {
+        // All notes are out of range four octaves up, until the transposition is undone
+        let options = PlayerOptions { tracks: vec![(0, 0)].into(), transpose: 48, out_of_range: OutOfRange::Drop, ..PlayerOptions::default() };
+        let backend = record_with_controls(2, options, vec![Control::SetTranspose(0)]);
+
+        assert_eq!(backend.commands(), &[
+            command(0, 0, 262),
+            command(0, 1, 330),
+            command(500, 0, 0),
+            command(500, 1, 0),
+            command(500, 0, 392),
+            command(1000, 0, 0)
+        ]);
+    }
+
+    #[test]
+    fn controls_while_paused() {
+        // Nothing is sent while paused, the new notes only sound when resuming
+        let controls = vec![Control::Pause, Control::Seek(Duration::from_millis(250)), Control::SetTranspose(12), Control::Resume];
+        let backend = record_with_controls(2, PlayerOptions { tracks: vec![(0, 0)].into(), ..PlayerOptions::default() }, controls);
+
+        assert_eq!(backend.commands(), &[
+            command(0, 0, 262),
+            command(0, 1, 330),
+            command(0, 0, 0),
+            command(0, 1, 0),
+            command(0, 0, 523),
+            command(0, 1, 659),
+            command(250, 0, 0),
+            command(250, 1, 0),
+            command(250, 0, 784),
+            command(750, 0, 0)
+        ]);
+    }
+
+    #[test]
+    fn spawn_uses_the_virtual_clock() {
+        // This would take 100 seconds if the playback thread waited in real time
+        let song = Song::from_bytes(CHORD).unwrap();
+        let player = Player::with_backend(RecordingBackend::new(), 2, VoicePolicy::DropNew);
+        let options = PlayerOptions { tracks: vec![(0, 0)].into(), delay_mul: 100.0, ..PlayerOptions::default() };
+        let (player, _) = player.spawn(song, options).wait();
+
+        assert_eq!(player.into_backend().elapsed(), Duration::from_secs(100));
+    }
+
+    #[test]
+    fn looped_bars() {
+        // With bars of a single quarter note, the first bar holds the first chord
+        let mut song = Song::from_bytes(CHORD).unwrap();
+        song.time_signatures = vec![TimeSignature { tick: 0, numerator: 1, denominator: 4 }];
+
+        let region = Loop { start: Position::Bar(1), end: Position::Bar(2), count: Some(2) };
+        let mut player = Player::with_backend(RecordingBackend::new(), 2, VoicePolicy::DropNew);
+        player.play_song(song, PlayerOptions { tracks: vec![(0, 0)].into(), looped: Some(region), ..PlayerOptions::default() });
+
+        assert_eq!(player.into_backend().commands(), &[
+            command(0, 0, 262),
+            command(0, 1, 330),
+            command(500, 0, 0),
+            command(500, 1, 0),
+            command(500, 0, 262),
+            command(500, 1, 330),
+            command(1000, 0, 0),
+            command(1000, 1, 0),
+            command(1000, 0, 392),
+            command(1500, 0, 0)
+        ]);
+    }
+
+    #[test]
+    fn start_at_bar() {
+        let mut song = Song::from_bytes(CHORD).unwrap();
+        song.time_signatures = vec![TimeSignature { tick: 0, numerator: 1, denominator: 4 }];
+
+        let mut player = Player::with_backend(RecordingBackend::new(), 2, VoicePolicy::DropNew);
+        player.play_song(song, PlayerOptions { tracks: vec![(0, 0)].into(), start: Position::Bar(2), ..PlayerOptions::default() });
+
+        assert_eq!(player.into_backend().commands(), &[
+            command(0, 0, 392),
+            command(500, 0, 0)
+        ]);
+    }
+
+    #[test]
+    fn virtual_clock_is_never_late() {
+        let song = Song::from_bytes(CHORD).unwrap();
+        let mut player = Player::with_backend(RecordingBackend::new(), 2, VoicePolicy::DropNew);
+        let report = player.play_song(song, PlayerOptions { tracks: vec![(0, 0)].into(), ..PlayerOptions::default() });
+
+        assert_eq!(report.lateness.len(), 6);
+        assert_eq!(report.max_lateness(), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn chord_on_one_buzzer() {
+        let options = PlayerOptions { tracks: vec![(0, 0)].into(), delay_mul: 2.0, ..PlayerOptions::default() };
+        let backend = record(1, options);
+
+        assert_eq!(backend.commands(), &[
+            command(0, 0, 262),
+            command(1000, 0, 0),
+            command(1000, 0, 392),
+            command(2000, 0, 0)
+        ]);
+    }
+}
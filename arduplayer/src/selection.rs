@@ -0,0 +1,139 @@
+//! Automatic selection of the tracks to play with a limited amount of buzzers
+
+use analysis::TrackStats;
+use song::{self, Event, Song, Track};
+
+/// A track that could be played, along with the information used to rank it
+struct Candidate {
+    index: usize,
+    /// Average pitch of the notes of the track
+    register: f64,
+    /// Notes per second
+    density: f64
+}
+
+impl Candidate {
+    /// Return the candidate for the track, or `None` if it starts no notes (e.g. it only
+    /// stops notes), since it has nothing to play
+    fn new(index: usize, track: &Track) -> Option<Candidate> {
+        let tones: Vec<_> = track.events().iter().filter_map(|e| match *e {
+            Event::Play { tone, .. } => Some(tone as f64),
+            _ => None
+        }).collect();
+
+        if tones.is_empty() {
+            return None;
+        }
+
+        let stats = TrackStats::new(track);
+
+        let register = tones.iter().sum::<f64>() / tones.len() as f64;
+        let seconds = stats.duration.as_secs() as f64 + stats.duration.subsec_nanos() as f64 / 1e9;
+        let density = stats.notes as f64 / seconds.max(1.0);
+        Some(Candidate { index, register, density })
+    }
+}
+
+/// Choose the tracks to play on the given amount of buzzers, so that no notes are
+/// dropped because all buzzers are busy
+///
+/// Tracks are picked in order of importance: first the melody, then the bass and then
+/// the harmony, from the busiest to the quietest track. A track is skipped if adding
+/// it would require more buzzers than available. Tracks without notes and percussion
+/// tracks are never chosen
+pub fn select_tracks(song: &Song, buzzers: u8) -> Vec<usize> {
+    let mut candidates: Vec<_> = song.tracks.iter().enumerate()
+        .filter(|&(_, track)| !track.is_percussion())
+        .filter_map(|(i, track)| Candidate::new(i, track))
+        .collect();
+
+    let mut ranked = Vec::with_capacity(candidates.len());
+
+    // The melody is usually the highest of the busy tracks (which leaves out
+    // high-pitched tracks that only play a couple of notes)
+    let max_density = candidates.iter().map(|c| c.density).fold(0.0, f64::max);
+    if let Some(melody) = position_by(&candidates, |c| c.density >= max_density / 2.0, |c| c.register) {
+        ranked.push(candidates.swap_remove(melody));
+    }
+
+    // The bass is the lowest of the remaining tracks
+    if let Some(bass) = position_by(&candidates, |_| true, |c| -c.register) {
+        ranked.push(candidates.swap_remove(bass));
+    }
+
+    // The rest of the tracks are harmony, the busiest ones being the most relevant
+    candidates.sort_by(|a, b| b.density.partial_cmp(&a.density).unwrap());
+    ranked.extend(candidates);
+
+    let mut selected: Vec<usize> = Vec::new();
+    for candidate in ranked {
+        let tracks = selected.iter().chain(Some(&candidate.index)).map(|&i| song.tracks[i].clone()).collect();
+        if TrackStats::new(&song::merge_tracks(tracks)).max_polyphony <= buzzers as usize {
+            selected.push(candidate.index);
+        }
+    }
+
+    selected.sort();
+    selected
+}
+
+/// Return the position of the candidate with the highest key among those matching the filter
+fn position_by<F, K>(candidates: &[Candidate], filter: F, key: K) -> Option<usize>
+    where F: Fn(&Candidate) -> bool, K: Fn(&Candidate) -> f64
+{
+    candidates.iter().enumerate()
+        .filter(|&(_, c)| filter(c))
+        .max_by(|&(_, a), &(_, b)| key(a).partial_cmp(&key(b)).unwrap())
+        .map(|(i, _)| i)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::select_tracks;
+    use song::{Event, Song, Track};
+    use tempo::{TempoMap, TimeBase};
+
+    /// A track playing each chord for the given time, on the given channel
+    fn track(chords: &[&[u8]], micros: u32, channel: u8) -> Track {
+        let mut events = Vec::new();
+        for chord in chords {
+            events.extend(chord.iter().map(|&tone| Event::Play { tone, velocity: 100, channel, track: 0 }));
+            events.push(Event::Wait(micros));
+            events.extend(chord.iter().map(|&tone| Event::Stop { tone, channel, track: 0 }));
+        }
+
+        Track::new(events)
+    }
+
+    fn song() -> Song {
+        let tracks = vec![
+            Track::new(vec![Event::Wait(4_000_000)]),
+            // Drums, busier than any other track
+            track(&[&[36, 42][..]; 32], 125_000, 9),
+            // Melody
+            track(&[&[72], &[74], &[76], &[77], &[79], &[77], &[76], &[74]], 500_000, 0),
+            // Bass
+            track(&[&[36], &[43], &[36], &[43]], 1_000_000, 1),
+            // Harmony
+            track(&[&[60, 64], &[62, 65], &[60, 64], &[59, 62]], 1_000_000, 2),
+            // A single high note, which is too sparse to be the melody
+            track(&[&[96]], 4_000_000, 3),
+            // Only note offs
+            Track::new(vec![Event::Wait(1000), Event::Stop { tone: 60, channel: 4, track: 0 }])
+        ];
+
+        Song { time_base: TimeBase::TicksPerQuarter(96), tempo_map: TempoMap::default(), time_signatures: Vec::new(), tracks, warnings: Vec::new() }
+    }
+
+    #[test]
+    fn selection() {
+        let song = song();
+        assert_eq!(select_tracks(&song, 0), vec![]);
+        assert_eq!(select_tracks(&song, 1), vec![2]);
+        assert_eq!(select_tracks(&song, 2), vec![2, 3]);
+        // The harmony doesn't fit with 3 buzzers, but the high note does
+        assert_eq!(select_tracks(&song, 3), vec![2, 3, 5]);
+        assert_eq!(select_tracks(&song, 4), vec![2, 3, 4]);
+        assert_eq!(select_tracks(&song, 5), vec![2, 3, 4, 5]);
+    }
+}
@@ -1,6 +1,6 @@
 //! Utility functions to deal with the serial port
 
-use std::io;
+use std::io::{self, Write};
 use std::process;
 use std::time::Duration;
 
@@ -8,8 +8,54 @@ use byteorder::{LittleEndian, WriteBytesExt};
 use serialport;
 use serialport::prelude::*;
 
+use backend::Backend;
 use util;
 
+/// A backend that sends the notes to an Arduino through the serial port
+pub struct SerialBackend {
+    port: Box<dyn SerialPort>,
+    /// Buzzers that are currently playing a note
+    sounding: Vec<u8>
+}
+
+impl SerialBackend {
+    /// Use the given serial port
+    pub fn new(port: Box<dyn SerialPort>) -> SerialBackend {
+        SerialBackend { port, sounding: Vec::new() }
+    }
+
+    /// Open the serial port of the Arduino, asking the user to choose if there are several
+    ///
+    /// See `get_port_name` for details
+    pub fn open() -> Result<SerialBackend, serialport::Error> {
+        let port_name = get_port_name();
+        Ok(SerialBackend::new(open_port(&port_name)?))
+    }
+}
+
+impl Backend for SerialBackend {
+    fn set_frequency(&mut self, buzzer: u8, freq: u16) -> io::Result<()> {
+        self.sounding.retain(|&b| b != buzzer);
+        if freq != 0 {
+            self.sounding.push(buzzer);
+        }
+
+        write_note(&mut *self.port, buzzer, freq)
+    }
+
+    fn silence_all(&mut self) -> io::Result<()> {
+        for buzzer in self.sounding.drain(..) {
+            write_note(&mut *self.port, buzzer, 0)?;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.port.flush()
+    }
+}
+
 /// Detect available serial ports:
 ///
 /// * If no ports are available, terminate the program
@@ -1,56 +1,151 @@
 use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::ops::RangeInclusive;
 use std::path::Path;
+use std::time::Duration;
 
+use analysis::{SongAnalysis, TrackStats};
+use error::SongError;
 use midi_parser::MidiParser;
-use util;
+use midi_writer::{self, MidiFormat};
+use tempo::{self, TempoMap, TimeBase, TimeSignature};
+use util::{self, OutOfRange};
 
 /// A song
 pub struct Song {
-    pub time_base: u16,
-    pub tracks: Vec<Track>
+    /// The meaning of a tick in the original MIDI file
+    pub time_base: TimeBase,
+    /// The tempo changes of the song, used to convert ticks to real time
+    pub tempo_map: TempoMap,
+    /// The time signature changes of the song, sorted by position
+    pub time_signatures: Vec<TimeSignature>,
+    pub tracks: Vec<Track>,
+    /// Problems found in the file when it was loaded in lenient mode
+    pub warnings: Vec<SongError>
 }
 
+/// Options to be used when loading a song
+#[derive(Clone, Default)]
+pub struct LoadOptions {
+    /// Load as much as possible from damaged files, collecting the problems in
+    /// `Song::warnings` instead of failing
+    pub lenient: bool,
+    /// Turn each track into one track per MIDI channel, so the instruments of format 0
+    /// files (which have a single track) can be chosen separately in `PlayerOptions`
+    pub split_channels: bool
+}
+
+/// MIDI channel 10, counting from 0
+const PERCUSSION_CHANNEL: u8 = 9;
+
+/// The "Percussive" family of General MIDI programs (Tinkle Bell to Reverse Cymbal)
+const PERCUSSION_PROGRAMS: RangeInclusive<u8> = 112..=119;
+
 /// A track
+#[derive(Clone)]
 pub struct Track {
+    /// The name of the track, if the MIDI file specified one
+    pub name: Option<String>,
+    /// The General MIDI program (instrument) of the track, if the MIDI file specified one
+    pub program: Option<u8>,
+    /// Amount of MIDI events that were ignored because they are not supported
+    pub unknown_events: u32,
     events: Vec<Event>
 }
 
 impl Track {
     /// Create a new track, based on a series of events
     pub fn new(events: Vec<Event>) -> Track {
-        Track { events }
+        Track { name: None, program: None, unknown_events: 0, events }
     }
 
-    /// Transpose this track
-    pub fn transpose(mut self, octaves: i8) -> Track {
-        for event in &mut self.events {
+    /// Transpose this track by whole octaves, handling notes that end up outside of the
+    /// range supported by the buzzers according to `policy`
+    pub fn transpose(self, octaves: i8, policy: OutOfRange) -> Track {
+        self.transpose_semitones(octaves as i16 * 12, policy)
+    }
+
+    /// Transpose this track by the given amount of semitones, handling notes that end up
+    /// outside of the range supported by the buzzers according to `policy`
+    pub fn transpose_semitones(mut self, semitones: i16, policy: OutOfRange) -> Track {
+        self.events = self.events.into_iter().filter_map(|mut event| {
             match event {
-                Event::Play { tone, .. }
-                | Event::Stop { tone, .. } => *tone = util::transpose(*tone, octaves),
-                _ => ()
+                Event::Play { ref mut tone, .. }
+                | Event::Stop { ref mut tone, .. } => *tone = util::fit_in_range(util::transpose(*tone, semitones), policy)?,
+                Event::Wait(_) => ()
             }
-        }
+
+            Some(event)
+        }).collect();
 
         self
     }
 
+    /// Return the octave shift that puts the most notes of this track in the range
+    /// supported by the buzzers, preferring the smallest shift in case of a tie
+    ///
+    /// The track is assumed to be transposed by `semitones` on top of the octave shift
+    pub fn best_octave_shift(&self, semitones: i16) -> i8 {
+        let tones: Vec<_> = self.events.iter().filter_map(|e| match *e {
+            Event::Play { tone, .. } => Some(tone),
+            _ => None
+        }).collect();
+
+        let in_range = |octaves| tones.iter().filter(|&&t| util::fit_in_range(util::transpose(t, octaves as i16 * 12 + semitones), OutOfRange::Drop).is_some()).count();
+        (-5..=5).max_by_key(|&octaves: &i8| (in_range(octaves), -octaves.abs())).unwrap()
+    }
+
+    /// Record `index` as the position of this track in `Song::tracks`, in all its events
+    pub fn set_index(&mut self, index: usize) {
+        for event in &mut self.events {
+            event.set_track(index);
+        }
+    }
+
     /// Return a slice into the events of this Track
     pub fn events(&self) -> &[Event] {
         &self.events
     }
+
+    /// Return true if this track has no notes (e.g. it only holds the tempo map)
+    pub fn is_empty(&self) -> bool {
+        !self.events.iter().any(|e| !e.is_wait())
+    }
+
+    /// Return true if this track plays drums or other unpitched percussion, which
+    /// sounds like noise on a buzzer
+    ///
+    /// These are tracks that only use MIDI channel 10 (reserved for percussion by General MIDI)
+    /// or one of the General MIDI percussive programs. Tracks that mix channel 10 with other
+    /// channels, as in format 0 files, are not percussion since they also hold the melody
+    pub fn is_percussion(&self) -> bool {
+        self.channels() == [PERCUSSION_CHANNEL] || self.program.iter().any(|p| PERCUSSION_PROGRAMS.contains(p))
+    }
+
+    /// Return the MIDI channels used by the notes of this track, in ascending order
+    pub fn channels(&self) -> Vec<u8> {
+        let mut channels: Vec<_> = self.events.iter().filter_map(|e| e.channel()).collect();
+        channels.sort();
+        channels.dedup();
+        channels
+    }
 }
 
 /// An event
 #[derive(Copy, Clone)]
 pub enum Event {
-    /// Start playing the tone with the given velocity
+    /// Start playing the tone with the given velocity, on the given MIDI channel (0-15)
     ///
     /// Note: the velocity is used to indicate the volume, but we don't use it
     /// because buzzers can only be turned on and off
-    Play { tone: u8, velocity: u8 },
-    /// Stop playing the tone
-    Stop { tone: u8 },
-    /// Wait for a given amount of milliseconds
+    ///
+    /// The `track` is the index of the event's track in `Song::tracks`, so it is
+    /// known even after merging tracks
+    Play { tone: u8, velocity: u8, channel: u8, track: usize },
+    /// Stop playing the tone on the given MIDI channel
+    Stop { tone: u8, channel: u8, track: usize },
+    /// Wait for a given amount of microseconds
     Wait(u32)
 }
 
@@ -62,6 +157,22 @@ impl Event {
         }
     }
 
+    /// Return the MIDI channel of a `Play` or `Stop` event
+    pub fn channel(self) -> Option<u8> {
+        match self {
+            Event::Play { channel, .. } | Event::Stop { channel, .. } => Some(channel),
+            Event::Wait(_) => None
+        }
+    }
+
+    /// Set the track of a `Play` or `Stop` event
+    pub fn set_track(&mut self, index: usize) {
+        match self {
+            Event::Play { track, .. } | Event::Stop { track, .. } => *track = index,
+            Event::Wait(_) => ()
+        }
+    }
+
     pub fn unwrap_wait(&mut self) -> &mut u32 {
         match self {
             Event::Wait(x) => x,
@@ -72,10 +183,94 @@ impl Event {
 
 impl Song {
     /// Create a song from a midi file
+    pub fn from_midi<P: AsRef<Path>>(path: P) -> Result<Song, SongError> {
+        Song::from_midi_with(path, &LoadOptions::default())
+    }
+
+    /// Create a song from a midi file, using the given `LoadOptions`
+    pub fn from_midi_with<P: AsRef<Path>>(path: P, options: &LoadOptions) -> Result<Song, SongError> {
+        let file = File::open(path)?;
+        Song::from_reader_with(BufReader::new(file), options)
+    }
+
+    /// Create a song from the contents of a midi file, e.g. obtained through `include_bytes!`
+    pub fn from_bytes(bytes: &[u8]) -> Result<Song, SongError> {
+        Song::from_reader(bytes)
+    }
+
+    /// Create a song from a source of midi data
     ///
-    /// Note: this function will panic if the file does not exist
-    pub fn from_midi<P: AsRef<Path>>(path: P) -> Song {
-        MidiParser::load_song(path.as_ref())
+    /// Note: the data is read in small pieces, so wrap unbuffered sources in a `BufReader`
+    pub fn from_reader<R: Read>(source: R) -> Result<Song, SongError> {
+        Song::from_reader_with(source, &LoadOptions::default())
+    }
+
+    /// Create a song from a source of midi data, using the given `LoadOptions`
+    pub fn from_reader_with<R: Read>(source: R, options: &LoadOptions) -> Result<Song, SongError> {
+        MidiParser::load_song(source, options)
+    }
+
+    /// Compute statistics for each track and for the whole song, e.g. to find out how
+    /// many buzzers are needed to play it
+    pub fn analyze(&self) -> SongAnalysis {
+        SongAnalysis {
+            tracks: self.tracks.iter().map(TrackStats::new).collect(),
+            merged: TrackStats::new(&merge_tracks(self.tracks.clone()))
+        }
+    }
+
+    /// Return the time at which the given bar starts, at the original tempo of the song
+    ///
+    /// Bars are numbered from 1, like in scores, and the time signature is 4/4 until the
+    /// first change. Changes are assumed to happen at the start of a bar. When using SMPTE
+    /// timing, where beats are not defined, bars are assumed to be in 4/4 at 120 bpm
+    pub fn bar_time(&self, bar: u32) -> Duration {
+        let mut bars = bar.saturating_sub(1) as u64;
+        let ticks_per_quarter = match self.time_base {
+            TimeBase::TicksPerQuarter(ticks) => ticks as u64,
+            TimeBase::Smpte { .. } => return Duration::from_micros(bars * 4 * tempo::DEFAULT_TEMPO as u64)
+        };
+
+        let mut tick = 0;
+        let mut signature = (4, 4);
+        let mut changes = self.time_signatures.iter().peekable();
+        loop {
+            while let Some(change) = changes.peek().cloned() {
+                if change.tick as u64 > tick {
+                    break;
+                }
+
+                signature = (change.numerator as u64, change.denominator as u64);
+                changes.next();
+            }
+
+            let bar_ticks = signature.0 * ticks_per_quarter * 4 / signature.1;
+            match changes.peek() {
+                // A change in the middle of a bar starts a new one
+                Some(change) if bars > 0 => {
+                    tick = ::std::cmp::min(tick + bar_ticks, change.tick as u64);
+                    bars -= 1;
+                }
+                _ => {
+                    tick += bars * bar_ticks;
+                    break;
+                }
+            }
+        }
+
+        let tick = ::std::cmp::min(tick, u32::MAX as u64) as u32;
+        Duration::from_micros(self.tempo_map.ticks_to_micros(tick, self.time_base))
+    }
+
+    /// Save this song as a midi file at the given path
+    pub fn save_midi<P: AsRef<Path>>(&self, path: P, format: MidiFormat) -> io::Result<()> {
+        let file = File::create(path)?;
+        self.write_midi(BufWriter::new(file), format)
+    }
+
+    /// Write this song to `dest` as a midi file
+    pub fn write_midi<W: Write>(&self, dest: W, format: MidiFormat) -> io::Result<()> {
+        midi_writer::write_song(self, format, dest)
     }
 }
 
@@ -125,3 +320,125 @@ fn pop_non_waits(track: &mut VecDeque<Event>, buf: &mut Vec<Event>) {
         buf.push(track.pop_front().unwrap());
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{Event, Song, Track};
+    use tempo::{TempoChange, TempoMap, TimeBase, TimeSignature};
+    use util::OutOfRange;
+
+    /// A track playing the given notes one after another
+    fn melody(tones: &[u8]) -> Track {
+        Track::new(tones.iter().flat_map(|&tone| vec![
+            Event::Play { tone, velocity: 100, channel: 0, track: 0 },
+            Event::Wait(1000),
+            Event::Stop { tone, channel: 0, track: 0 }
+        ]).collect())
+    }
+
+    /// Return the notes of the track, as (is a Play event, tone) pairs
+    fn tones(track: &Track) -> Vec<(bool, u8)> {
+        track.events().iter().filter_map(|e| match *e {
+            Event::Play { tone, .. } => Some((true, tone)),
+            Event::Stop { tone, .. } => Some((false, tone)),
+            Event::Wait(_) => None
+        }).collect()
+    }
+
+    #[test]
+    fn transpose_semitones() {
+        let track = melody(&[22, 60, 104]);
+        assert_eq!(tones(&track.clone().transpose_semitones(5, OutOfRange::Fold)),
+            vec![(true, 27), (false, 27), (true, 65), (false, 65), (true, 97), (false, 97)]);
+        assert_eq!(tones(&track.clone().transpose_semitones(5, OutOfRange::Clamp)),
+            vec![(true, 27), (false, 27), (true, 65), (false, 65), (true, 107), (false, 107)]);
+        assert_eq!(tones(&track.clone().transpose_semitones(5, OutOfRange::Drop)),
+            vec![(true, 27), (false, 27), (true, 65), (false, 65)]);
+
+        assert_eq!(tones(&track.clone().transpose_semitones(-7, OutOfRange::Fold)),
+            vec![(true, 27), (false, 27), (true, 53), (false, 53), (true, 97), (false, 97)]);
+        assert_eq!(tones(&track.clone().transpose_semitones(-7, OutOfRange::Clamp)),
+            vec![(true, 24), (false, 24), (true, 53), (false, 53), (true, 97), (false, 97)]);
+        assert_eq!(tones(&track.transpose_semitones(-7, OutOfRange::Drop)),
+            vec![(true, 53), (false, 53), (true, 97), (false, 97)]);
+    }
+
+    #[test]
+    fn best_octave_shift() {
+        assert_eq!(melody(&[]).best_octave_shift(0), 0);
+        assert_eq!(melody(&[24, 60, 107]).best_octave_shift(0), 0);
+        assert_eq!(melody(&[12, 16, 23]).best_octave_shift(0), 1);
+        assert_eq!(melody(&[108, 119]).best_octave_shift(0), -1);
+        assert_eq!(melody(&[0, 5]).best_octave_shift(0), 2);
+        assert_eq!(melody(&[127]).best_octave_shift(0), -2);
+
+        // The most notes in range win, even if some are left out
+        assert_eq!(melody(&[20, 100, 105, 110, 115]).best_octave_shift(0), -1);
+
+        // Shifting by a single octave is enough, even if others fit too
+        assert_eq!(melody(&[30, 40]).best_octave_shift(-12), 1);
+
+        // The transposition applies on top of the shift
+        assert_eq!(melody(&[100]).best_octave_shift(12), -1);
+        assert_eq!(melody(&[30]).best_octave_shift(-7), 1);
+    }
+
+    /// An empty song at 96 ticks per quarter and 120 bpm, with the given time signatures
+    fn song(signatures: &[(u32, u8, u8)]) -> Song {
+        Song {
+            time_base: TimeBase::TicksPerQuarter(96),
+            tempo_map: TempoMap::default(),
+            time_signatures: signatures.iter().map(|&(tick, numerator, denominator)| TimeSignature { tick, numerator, denominator }).collect(),
+            tracks: Vec::new(),
+            warnings: Vec::new()
+        }
+    }
+
+    #[test]
+    fn bar_time() {
+        let song = song(&[]);
+        assert_eq!(song.bar_time(0), Duration::from_secs(0));
+        assert_eq!(song.bar_time(1), Duration::from_secs(0));
+        assert_eq!(song.bar_time(2), Duration::from_secs(2));
+        assert_eq!(song.bar_time(5), Duration::from_secs(8));
+    }
+
+    #[test]
+    fn bar_time_with_signatures() {
+        assert_eq!(song(&[(0, 3, 4)]).bar_time(2), Duration::from_millis(1500));
+        assert_eq!(song(&[(0, 6, 8)]).bar_time(3), Duration::from_secs(3));
+
+        // Two bars of 4/4, then 3/4
+        let song = song(&[(0, 4, 4), (768, 3, 4)]);
+        assert_eq!(song.bar_time(3), Duration::from_secs(4));
+        assert_eq!(song.bar_time(4), Duration::from_millis(5500));
+    }
+
+    #[test]
+    fn bar_time_with_change_in_the_middle_of_a_bar() {
+        // The change to 3/4 after a bar and a half of 4/4 starts the third bar
+        let song = song(&[(576, 3, 4)]);
+        assert_eq!(song.bar_time(2), Duration::from_secs(2));
+        assert_eq!(song.bar_time(3), Duration::from_secs(3));
+        assert_eq!(song.bar_time(4), Duration::from_millis(4500));
+    }
+
+    #[test]
+    fn bar_time_with_tempo_change() {
+        // 240 bpm from the second bar
+        let mut song = song(&[]);
+        song.tempo_map = TempoMap::new(vec![TempoChange { tick: 384, micros_per_quarter: 250_000 }]);
+        assert_eq!(song.bar_time(2), Duration::from_secs(2));
+        assert_eq!(song.bar_time(3), Duration::from_secs(3));
+    }
+
+    #[test]
+    fn bar_time_with_smpte() {
+        let mut song = song(&[(0, 3, 4)]);
+        song.time_base = TimeBase::Smpte { fps: 25, ticks_per_frame: 40 };
+        assert_eq!(song.bar_time(1), Duration::from_secs(0));
+        assert_eq!(song.bar_time(3), Duration::from_secs(4));
+    }
+}
@@ -0,0 +1,148 @@
+//! Conversion of MIDI ticks to real time
+
+/// Tempo used until the first Set Tempo event (120 bpm), as mandated by the MIDI spec
+pub const DEFAULT_TEMPO: u32 = 500_000;
+
+/// The time division of a MIDI file, which determines the meaning of a tick
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TimeBase {
+    /// Ticks per quarter note, so the duration of a tick depends on the tempo
+    TicksPerQuarter(u16),
+    /// SMPTE timing, where a tick has a fixed duration regardless of the tempo
+    ///
+    /// Note: an `fps` of 29 stands for 29.97 frames per second (30 drop frame)
+    Smpte { fps: u8, ticks_per_frame: u8 }
+}
+
+impl TimeBase {
+    /// Decode the division word of a MIDI header, or return `None` if it is invalid
+    ///
+    /// A valid division has a nonzero amount of ticks, and SMPTE timing must use one of
+    /// the standard frame rates (24, 25, 29.97 or 30 fps)
+    pub fn from_division(division: u16) -> Option<TimeBase> {
+        // If the top bit is set, the high byte holds the negated frames per second
+        if division & 0x8000 != 0 {
+            let fps = ((division >> 8) as u8 as i8).wrapping_neg() as u8;
+            let ticks_per_frame = division as u8;
+            if [24, 25, 29, 30].contains(&fps) && ticks_per_frame > 0 {
+                Some(TimeBase::Smpte { fps, ticks_per_frame })
+            } else {
+                None
+            }
+        } else if division > 0 {
+            Some(TimeBase::TicksPerQuarter(division))
+        } else {
+            None
+        }
+    }
+
+    /// Encode this time base as the division word of a MIDI header
+    pub fn to_division(self) -> u16 {
+        match self {
+            TimeBase::TicksPerQuarter(ticks) => ticks,
+            TimeBase::Smpte { fps, ticks_per_frame } => ((fps as i8).wrapping_neg() as u8 as u16) << 8 | ticks_per_frame as u16
+        }
+    }
+}
+
+/// A change of tempo at a given point in the song
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TempoChange {
+    /// Absolute position of the change, in ticks
+    pub tick: u32,
+    /// The new tempo, in microseconds per quarter note
+    pub micros_per_quarter: u32
+}
+
+/// A change of time signature at a given point in the song
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TimeSignature {
+    /// Absolute position of the change, in ticks
+    pub tick: u32,
+    /// Beats per bar
+    pub numerator: u8,
+    /// Note value of a beat (4 for quarter notes, 8 for eighth notes...)
+    pub denominator: u8
+}
+
+/// The tempo changes of a song, sorted by position
+#[derive(Clone, Debug, Default)]
+pub struct TempoMap {
+    changes: Vec<TempoChange>
+}
+
+impl TempoMap {
+    /// Create a tempo map from a list of changes, in any order
+    pub fn new(mut changes: Vec<TempoChange>) -> TempoMap {
+        // Stable sort, so the last of several changes at the same tick wins
+        changes.sort_by_key(|c| c.tick);
+        TempoMap { changes }
+    }
+
+    /// Return a slice into the tempo changes, sorted by position
+    pub fn changes(&self) -> &[TempoChange] {
+        &self.changes
+    }
+
+    /// Convert an absolute position in ticks to microseconds since the start of the song
+    ///
+    /// Note: the tempo is ignored when using SMPTE timing
+    pub fn ticks_to_micros(&self, tick: u32, time_base: TimeBase) -> u64 {
+        let time_base = match time_base {
+            TimeBase::TicksPerQuarter(ticks) => ticks,
+            TimeBase::Smpte { fps: 29, ticks_per_frame } => {
+                return tick as u64 * 100_000_000 / (2997 * ticks_per_frame as u64);
+            }
+            TimeBase::Smpte { fps, ticks_per_frame } => {
+                return tick as u64 * 1_000_000 / (fps as u64 * ticks_per_frame as u64);
+            }
+        };
+
+        // Accumulate `ticks * tempo` and divide at the end, so no rounding errors build up
+        let mut acc = 0;
+        let mut last_tick = 0;
+        let mut tempo = DEFAULT_TEMPO;
+        for change in self.changes.iter().take_while(|c| c.tick < tick) {
+            acc += (change.tick - last_tick) as u64 * tempo as u64;
+            last_tick = change.tick;
+            tempo = change.micros_per_quarter;
+        }
+
+        acc += (tick - last_tick) as u64 * tempo as u64;
+        acc / time_base as u64
+    }
+
+    /// Convert microseconds since the start of the song to an absolute position in ticks,
+    /// rounded to the nearest tick
+    pub fn micros_to_ticks(&self, micros: u64, time_base: TimeBase) -> u32 {
+        let time_base = match time_base {
+            TimeBase::TicksPerQuarter(ticks) => ticks as u64,
+            TimeBase::Smpte { fps: 29, ticks_per_frame } => {
+                return ((micros * 2997 * ticks_per_frame as u64 + 50_000_000) / 100_000_000) as u32;
+            }
+            TimeBase::Smpte { fps, ticks_per_frame } => {
+                return ((micros * fps as u64 * ticks_per_frame as u64 + 500_000) / 1_000_000) as u32;
+            }
+        };
+
+        // Work in the same `ticks * tempo` units as `ticks_to_micros`
+        let target = micros * time_base;
+        let mut acc = 0;
+        let mut last_tick = 0;
+        let mut tempo = DEFAULT_TEMPO;
+        for change in &self.changes {
+            let next = acc + (change.tick - last_tick) as u64 * tempo as u64;
+            if next > target {
+                break;
+            }
+
+            acc = next;
+            last_tick = change.tick;
+            tempo = change.micros_per_quarter;
+        }
+
+        // A tempo of 0 is invalid, but we must not divide by it
+        let tempo = ::std::cmp::max(tempo, 1) as u64;
+        last_tick + ((target.saturating_sub(acc) + tempo / 2) / tempo) as u32
+    }
+}
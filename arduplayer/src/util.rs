@@ -1,18 +1,48 @@
 //! Utility functions
 
-pub fn transpose(midi_code: u8, octaves: i8) -> u8 {
-    let result = (midi_code as i16) + octaves as i16 * 12;
-    if 24 <= result && result <= 107 {
-        result as u8
-    } else {
-        panic!("Transpose out of bounds: {} by {} octaves", midi_code, octaves)
-    }
+/// Lowest note supported by the buzzers (C1)
+pub const LOWEST_NOTE: u8 = 24;
+/// Highest note supported by the buzzers (B7)
+pub const HIGHEST_NOTE: u8 = 107;
+
+/// What to do with notes that are outside of the range supported by the buzzers
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum OutOfRange {
+    /// Move the note by whole octaves until it is in range
+    Fold,
+    /// Replace the note by the closest one in range (C1 or B7)
+    Clamp,
+    /// Don't play the note
+    Drop
+}
+
+/// Transpose a note by the given amount of semitones, which may take it out of the valid
+/// range of midi codes
+pub fn transpose(midi_code: u8, semitones: i16) -> i16 {
+    midi_code as i16 + semitones
+}
+
+/// Bring a note into the range supported by the buzzers, following the given policy
+///
+/// Returns `None` if the note should not be played
+pub fn fit_in_range(note: i16, policy: OutOfRange) -> Option<u8> {
+    let (lowest, highest) = (LOWEST_NOTE as i16, HIGHEST_NOTE as i16);
+    let note = match policy {
+        _ if lowest <= note && note <= highest => note,
+        OutOfRange::Fold if note < lowest => note + (lowest - note + 11) / 12 * 12,
+        OutOfRange::Fold => note - (note - highest + 11) / 12 * 12,
+        OutOfRange::Clamp if note < lowest => lowest,
+        OutOfRange::Clamp => highest,
+        OutOfRange::Drop => return None
+    };
+
+    Some(note as u8)
 }
 
 pub fn midi_code_to_freq(note: u8) -> Option<u16> {
-    let in_range = 24 <= note && note <= 107;
+    let in_range = LOWEST_NOTE <= note && note <= HIGHEST_NOTE;
     if in_range {
-        Some(NOTE_FREQS[(note - 24) as usize])
+        Some(NOTE_FREQS[(note - LOWEST_NOTE) as usize])
     } else {
         None
     }
@@ -38,3 +68,47 @@ const NOTE_FREQS: &[u16] = &[33, 35, 37, 39, 41, 44, 46, 49, 52, 55, 58, 62, 65,
     277, 294, 311, 330, 349, 370, 392, 415, 440, 466, 494, 523, 554, 587, 622, 659, 698, 740, 784,
     831, 880, 932, 988, 1047, 1109, 1175, 1245, 1319, 1397, 1480, 1568, 1661, 1760, 1865, 1976,
     2093, 2217, 2349, 2489, 2637, 2794, 2960, 3136, 3322, 3520, 3729, 3951];
+
+#[cfg(test)]
+mod tests {
+    use super::{fit_in_range, transpose, OutOfRange};
+
+    #[test]
+    fn in_range() {
+        for &policy in &[OutOfRange::Fold, OutOfRange::Clamp, OutOfRange::Drop] {
+            assert_eq!(fit_in_range(24, policy), Some(24));
+            assert_eq!(fit_in_range(60, policy), Some(60));
+            assert_eq!(fit_in_range(107, policy), Some(107));
+        }
+    }
+
+    #[test]
+    fn fold() {
+        assert_eq!(fit_in_range(23, OutOfRange::Fold), Some(35));
+        assert_eq!(fit_in_range(12, OutOfRange::Fold), Some(24));
+        assert_eq!(fit_in_range(108, OutOfRange::Fold), Some(96));
+        assert_eq!(fit_in_range(119, OutOfRange::Fold), Some(107));
+        assert_eq!(fit_in_range(120, OutOfRange::Fold), Some(96));
+
+        // Several octaves away, including notes that are not valid midi codes
+        assert_eq!(fit_in_range(0, OutOfRange::Fold), Some(24));
+        assert_eq!(fit_in_range(-1, OutOfRange::Fold), Some(35));
+        assert_eq!(fit_in_range(131, OutOfRange::Fold), Some(107));
+        assert_eq!(fit_in_range(132, OutOfRange::Fold), Some(96));
+        assert_eq!(fit_in_range(transpose(127, 48), OutOfRange::Fold), Some(103));
+    }
+
+    #[test]
+    fn clamp() {
+        assert_eq!(fit_in_range(23, OutOfRange::Clamp), Some(24));
+        assert_eq!(fit_in_range(-40, OutOfRange::Clamp), Some(24));
+        assert_eq!(fit_in_range(108, OutOfRange::Clamp), Some(107));
+        assert_eq!(fit_in_range(200, OutOfRange::Clamp), Some(107));
+    }
+
+    #[test]
+    fn drop() {
+        assert_eq!(fit_in_range(23, OutOfRange::Drop), None);
+        assert_eq!(fit_in_range(108, OutOfRange::Drop), None);
+    }
+}
@@ -0,0 +1,101 @@
+//! Policies to decide which buzzer plays a new note, in particular when all of them are busy
+
+use std::collections::BTreeMap;
+
+/// A note that is being played on a buzzer
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Voice {
+    /// The note of the song that started the voice, which identifies it along with the
+    /// channel and the track
+    pub tone: u8,
+    /// The note that is played, which differs from `tone` if the song is transposed
+    pub note: u8,
+    pub velocity: u8,
+    pub channel: u8,
+    /// The track the note belongs to
+    pub track: usize,
+    /// The priority of the track, see `PlayerOptions::priorities`
+    pub priority: u8,
+    /// Sequence number of the note, so older notes have lower numbers
+    pub started: u64
+}
+
+/// A strategy to assign buzzers to new notes
+pub trait VoiceAllocator {
+    /// Return the buzzer that should play `voice`, or `None` to drop the note
+    ///
+    /// `buzzers[i]` holds the voice playing on buzzer `i`, if any. Returning a busy buzzer
+    /// means that the voice playing on it is stopped to make room for the new one
+    ///
+    /// Note: the scheduler overrides the choice when it conflicts with track priorities.
+    /// A note never steals the buzzer of a note with a higher priority, and it always
+    /// steals one from a lower priority note instead of being dropped
+    fn allocate(&mut self, voice: &Voice, buzzers: &[Option<Voice>]) -> Option<usize>;
+}
+
+/// Built-in voice allocation policies
+///
+/// All of them use a free buzzer if there is one. They only differ in what they do when
+/// all buzzers are busy
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum VoicePolicy {
+    /// Drop the new note
+    DropNew,
+    /// Stop the note that started first
+    StealOldest,
+    /// Stop the note with the lowest velocity, unless the new note is even quieter
+    StealLowestVelocity,
+    /// Stop the oldest note of the track with the lowest average velocity (the first such
+    /// track in case of a tie)
+    StealQuietestTrack,
+    /// Stop the lowest note, unless the new note is even lower
+    KeepHighest,
+    /// Stop the highest note, unless the new note is even higher
+    KeepLowest
+}
+
+impl VoiceAllocator for VoicePolicy {
+    fn allocate(&mut self, voice: &Voice, buzzers: &[Option<Voice>]) -> Option<usize> {
+        if let Some(free) = buzzers.iter().position(|b| b.is_none()) {
+            return Some(free);
+        }
+
+        if buzzers.is_empty() {
+            return None;
+        }
+
+        // All buzzers are busy, so we can safely unwrap their voices
+        let busy = buzzers.iter().map(|b| b.unwrap()).enumerate();
+        match *self {
+            VoicePolicy::DropNew => None,
+            VoicePolicy::StealOldest => busy.min_by_key(|&(_, v)| v.started).map(|(i, _)| i),
+            VoicePolicy::StealLowestVelocity => {
+                busy.min_by_key(|&(_, v)| (v.velocity, v.started))
+                    .filter(|&(_, v)| v.velocity <= voice.velocity)
+                    .map(|(i, _)| i)
+            }
+            VoicePolicy::StealQuietestTrack => {
+                let mut tracks = BTreeMap::new();
+                for (_, v) in busy.clone() {
+                    let entry = tracks.entry(v.track).or_insert((0, 0));
+                    entry.0 += v.velocity as u32;
+                    entry.1 += 1;
+                }
+
+                // Compare average velocities without dividing, by cross-multiplying. Tracks
+                // are visited in order, so ties go to the lowest track index
+                let (&quietest, _) = tracks.iter()
+                    .min_by(|&(_, &(sum_a, count_a)), &(_, &(sum_b, count_b))| (sum_a * count_b).cmp(&(sum_b * count_a)))
+                    .unwrap();
+
+                busy.filter(|&(_, v)| v.track == quietest).min_by_key(|&(_, v)| v.started).map(|(i, _)| i)
+            }
+            VoicePolicy::KeepHighest => {
+                busy.min_by_key(|&(_, v)| v.note).filter(|&(_, v)| v.note < voice.note).map(|(i, _)| i)
+            }
+            VoicePolicy::KeepLowest => {
+                busy.max_by_key(|&(_, v)| v.note).filter(|&(_, v)| v.note > voice.note).map(|(i, _)| i)
+            }
+        }
+    }
+}
@@ -0,0 +1,189 @@
+//! A backend that renders the buzzers to a WAV file instead of playing them
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use byteorder::{LittleEndian, WriteBytesExt};
+
+use backend::Backend;
+
+/// A backend that synthesizes the square waves played by the buzzers, so songs can be
+/// listened to without the hardware
+///
+/// Like on the Arduino, each buzzer plays a square wave with a 50% duty cycle. The waves
+/// of all buzzers are mixed into a single channel of 16-bit samples
+pub struct WavBackend {
+    sample_rate: u32,
+    /// Amplitude of a single buzzer, so all of them together never clip
+    amplitude: f64,
+    /// Frequency and phase (between 0 and 1) of each buzzer
+    buzzers: Vec<(u16, f64)>,
+    /// The instant the rendering started at, used as the origin of the backend's clock
+    start: Instant,
+    /// Time rendered so far
+    elapsed: Duration,
+    samples: Vec<i16>
+}
+
+impl WavBackend {
+    /// Create a backend rendering the given amount of buzzers at the given sample rate (in Hz)
+    pub fn new(sample_rate: u32, buzzers: u8) -> WavBackend {
+        WavBackend {
+            sample_rate,
+            amplitude: i16::MAX as f64 / buzzers.max(1) as f64,
+            buzzers: vec![(0, 0.0); buzzers as usize],
+            start: Instant::now(),
+            elapsed: Duration::from_secs(0),
+            samples: Vec::new()
+        }
+    }
+
+    /// Return the samples rendered so far
+    pub fn samples(&self) -> &[i16] {
+        &self.samples
+    }
+
+    /// Write the samples rendered so far as a mono 16-bit PCM WAV file
+    pub fn write_wav<W: Write>(&self, mut dest: W) -> io::Result<()> {
+        let data_len = self.samples.len() as u32 * 2;
+
+        dest.write_all(b"RIFF")?;
+        dest.write_u32::<LittleEndian>(36 + data_len)?;
+        dest.write_all(b"WAVE")?;
+
+        dest.write_all(b"fmt ")?;
+        dest.write_u32::<LittleEndian>(16)?;
+        // PCM format, one channel
+        dest.write_u16::<LittleEndian>(1)?;
+        dest.write_u16::<LittleEndian>(1)?;
+        dest.write_u32::<LittleEndian>(self.sample_rate)?;
+        // Bytes per second and per sample
+        dest.write_u32::<LittleEndian>(self.sample_rate * 2)?;
+        dest.write_u16::<LittleEndian>(2)?;
+        dest.write_u16::<LittleEndian>(16)?;
+
+        dest.write_all(b"data")?;
+        dest.write_u32::<LittleEndian>(data_len)?;
+        for &sample in &self.samples {
+            dest.write_i16::<LittleEndian>(sample)?;
+        }
+
+        dest.flush()
+    }
+
+    /// Save the samples rendered so far to a WAV file
+    pub fn save_wav<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        self.write_wav(BufWriter::new(File::create(path)?))
+    }
+}
+
+impl Backend for WavBackend {
+    fn set_frequency(&mut self, buzzer: u8, freq: u16) -> io::Result<()> {
+        let buzzer = buzzer as usize;
+        if buzzer >= self.buzzers.len() {
+            // Lower the volume, so the extra buzzers don't make the mix clip
+            self.buzzers.resize(buzzer + 1, (0, 0.0));
+            self.amplitude = i16::MAX as f64 / self.buzzers.len() as f64;
+        }
+
+        self.buzzers[buzzer].0 = freq;
+        Ok(())
+    }
+
+    fn silence_all(&mut self) -> io::Result<()> {
+        for buzzer in &mut self.buzzers {
+            buzzer.0 = 0;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn wait(&mut self, duration: Duration) {
+        self.elapsed += duration;
+
+        // Derive the amount of samples from the total time, so no rounding errors build up
+        let micros = self.elapsed.as_secs() * 1_000_000 + self.elapsed.subsec_micros() as u64;
+        let total = (micros * self.sample_rate as u64 / 1_000_000) as usize;
+
+        while self.samples.len() < total {
+            let mut sample = 0.0;
+            for &mut (freq, ref mut phase) in &mut self.buzzers {
+                if freq == 0 {
+                    continue;
+                }
+
+                sample += if *phase < 0.5 { self.amplitude } else { -self.amplitude };
+                *phase = (*phase + freq as f64 / self.sample_rate as f64).fract();
+            }
+
+            self.samples.push(sample as i16);
+        }
+    }
+
+    fn now(&self) -> Instant {
+        self.start + self.elapsed
+    }
+
+    fn is_realtime(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use byteorder::{ByteOrder, LittleEndian};
+
+    use super::WavBackend;
+    use backend::Backend;
+
+    #[test]
+    fn single_tone() {
+        // A 1 kHz square wave lasts 8 samples at 8 kHz
+        let mut backend = WavBackend::new(8000, 1);
+        backend.set_frequency(0, 1000).unwrap();
+        backend.wait(Duration::from_millis(10));
+
+        let period = [32767, 32767, 32767, 32767, -32767, -32767, -32767, -32767];
+        assert_eq!(backend.samples().len(), 80);
+        for chunk in backend.samples().chunks(8) {
+            assert_eq!(chunk, period);
+        }
+
+        let mut wav = Vec::new();
+        backend.write_wav(&mut wav).unwrap();
+        assert_eq!(wav.len(), 44 + 160);
+        assert_eq!(&wav[0..4], b"RIFF");
+        assert_eq!(LittleEndian::read_u32(&wav[4..8]), 36 + 160);
+        assert_eq!(&wav[8..16], b"WAVEfmt ");
+        assert_eq!(LittleEndian::read_u16(&wav[22..24]), 1);
+        assert_eq!(LittleEndian::read_u32(&wav[24..28]), 8000);
+        assert_eq!(LittleEndian::read_u16(&wav[34..36]), 16);
+        assert_eq!(&wav[36..40], b"data");
+        assert_eq!(LittleEndian::read_u32(&wav[40..44]), 160);
+        assert_eq!(LittleEndian::read_i16(&wav[44..46]), 32767);
+        assert_eq!(LittleEndian::read_i16(&wav[52..54]), -32767);
+    }
+
+    #[test]
+    fn extra_buzzers_do_not_clip() {
+        let mut backend = WavBackend::new(8000, 1);
+        backend.set_frequency(1, 500).unwrap();
+        backend.set_frequency(0, 1000).unwrap();
+        backend.wait(Duration::from_millis(2));
+
+        // Each of the two buzzers gets half of the range
+        assert_eq!(&backend.samples()[0..4], &[32767; 4]);
+        assert_eq!(&backend.samples()[4..8], &[0; 4]);
+        backend.set_frequency(1, 0).unwrap();
+        backend.wait(Duration::from_millis(3));
+        assert_eq!(&backend.samples()[16..20], &[16383; 4]);
+    }
+}
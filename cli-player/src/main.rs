@@ -1,34 +1,59 @@
 extern crate arduplayer;
-extern crate rand;
 
+use std::borrow::Cow;
 use std::collections::HashMap;
+use std::io::{self, BufRead};
+use std::sync::mpsc::{self, RecvTimeoutError};
 use std::{env, thread };
-use std::hash::Hash;
 use std::time::Duration;
 
-use arduplayer::{Player, PlayerOptions, Song};
-use rand::Rng;
+use arduplayer::{Player, PlayerOptions, Playlist, Repeat, Song, TrackStats, VoicePolicy, WavBackend};
 
 fn main() {
     let songs = songs();
 
     if let Some(song_name) = env::args().nth(1) {
-        let song_name = if song_name == "random" {
-            random_key(&songs)
+        if song_name == "analyze" {
+            let song_name = env::args().nth(2).expect("Please specify the song to analyze");
+            analyze(&song_name);
+            return;
+        }
+
+        if song_name == "render" {
+            let song_name = env::args().nth(2).expect("Please specify the song to render");
+            let output = env::args().nth(3).unwrap_or_else(|| format!("{}.wav", song_name));
+            render(&songs, &song_name, &output);
+            return;
+        }
+
+        // Random plays all songs in random order, over and over
+        let random = song_name == "random";
+        let song_names: Vec<String> = if random {
+            songs.keys().map(|name| name.to_string()).collect()
         } else {
-            &*song_name
+            env::args().skip(1).collect()
         };
 
-        let mut player = Player::new(6).expect("Could not initialize serial port");
+        let player = Player::new(6).expect("Could not initialize serial port");
 
         // Delay to get serial connection set up
         thread::sleep(Duration::from_millis(3000));
 
-        let song = Song::from_midi(format!("music/{}.mid", song_name));
-        let options = &songs[song_name];
-        player.play_song(song, options.borrow());
+        let mut playlist = Playlist::new();
+        for song_name in &song_names {
+            let song = Song::from_midi(format!("music/{}.mid", song_name)).expect("Could not load song");
+            let options = song_options(&songs, song_name, &song, player.buzzers());
+            playlist.push(song, options);
+        }
+
+        if random {
+            playlist.set_shuffle(true);
+            playlist.repeat = Repeat::All;
+        }
+
+        play(playlist, player);
     } else {
-        println!("Please specify a song:");
+        println!("Please specify one or more songs, or random to play all of them:");
         for song_name in songs.keys() {
             println!("* {}", song_name);
         }
@@ -36,73 +61,149 @@ fn main() {
 
 }
 
-fn random_key<K: Eq + Hash, V>(map: &HashMap<K, V>) -> &K {
-    let songs: Vec<_> = map.keys().collect();
-    let chosen = rand::thread_rng().choose(&songs);
-    *chosen.unwrap()
+/// Play the playlist, reading commands to control it from stdin
+fn play(playlist: Playlist, player: Player) {
+    let handle = playlist.spawn(player);
+
+    // Reading blocks, so it happens on its own thread
+    let (commands, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let stdin = io::stdin();
+        for line in stdin.lock().lines() {
+            if commands.send(line.unwrap_or_default()).is_err() {
+                break;
+            }
+        }
+    });
+
+    println!("Commands: n (next), p (previous), pause, resume, q (quit)");
+    while !handle.is_finished() {
+        match receiver.recv_timeout(Duration::from_millis(100)) {
+            Ok(command) => match command.trim() {
+                "n" => handle.skip(),
+                "p" => handle.previous(),
+                "pause" => handle.pause(),
+                "resume" => handle.resume(),
+                "q" => handle.stop(),
+                _ => println!("Unknown command")
+            },
+            Err(RecvTimeoutError::Timeout) => (),
+            // The input was closed, so let the playlist play until it ends
+            Err(RecvTimeoutError::Disconnected) => break
+        }
+    }
+
+    handle.wait();
+}
+
+/// Songs without hand-picked tracks play as many tracks as fit on the buzzers. Either
+/// way, tracks are transposed to fit the range of the buzzers
+fn song_options(songs: &HashMap<&str, PlayerOptions<'static>>, song_name: &str, song: &Song, buzzers: u8) -> PlayerOptions<'static> {
+    match songs.get(song_name) {
+        Some(options) => options.clone().fit_range(song),
+        None => PlayerOptions::fit_buzzers(song, buzzers)
+    }
+}
+
+fn render(songs: &HashMap<&str, PlayerOptions<'static>>, song_name: &str, output: &str) {
+    let song = Song::from_midi(format!("music/{}.mid", song_name)).expect("Could not load song");
+    let mut player = Player::with_backend(WavBackend::new(44_100, 6), 6, VoicePolicy::DropNew);
+
+    let options = song_options(songs, song_name, &song, player.buzzers());
+    player.play_song(song, options);
+    player.into_backend().save_wav(output).expect("Could not write WAV file");
+}
+
+fn analyze(song_name: &str) {
+    let song = Song::from_midi(format!("music/{}.mid", song_name)).expect("Could not load song");
+    let analysis = song.analyze();
+
+    println!("Tracks:");
+    for (index, (track, stats)) in song.tracks.iter().zip(&analysis.tracks).enumerate() {
+        println!("{}. {}{}", index, track.name.as_ref().map_or("<unknown>", |n| n), if track.is_percussion() { " (drums)" } else { "" });
+        print_stats(stats);
+    }
+
+    println!("All tracks:");
+    print_stats(&analysis.merged);
+}
+
+fn print_stats(stats: &TrackStats) {
+    let range = match stats.range {
+        Some((low, high)) => format!("{}-{}", low, high),
+        None => String::from("-")
+    };
+
+    println!("    notes: {}, min buzzers: {}, range: {}, out of range: {}, duration: {}s",
+        stats.notes,
+        stats.max_polyphony,
+        range,
+        stats.out_of_range,
+        stats.duration.as_secs()
+    );
 }
 
 fn songs() -> HashMap<&'static str, PlayerOptions<'static>> {
     let mut map = HashMap::new();
 
     map.insert("PkmRS-Center", PlayerOptions {
-        tracks: &[(1, 0), (2, 0), (3, 0), (4, 0), (5, 0)],
-        delay_mul: 5.0
+        tracks: Cow::Borrowed(&[(1, 0), (2, 0), (3, 0), (4, 0), (5, 0)]),
+        ..PlayerOptions::default()
     });
 
     map.insert("SSBKirbyStage", PlayerOptions {
-        tracks: &[(1, 0), (2, 0), (4, 0), (5, 0), (6, 0), (8, 0), (9, 0), (10, 0), (11, 2), (13, 0)],
-        delay_mul: 3.0
+        tracks: Cow::Borrowed(&[(1, 0), (2, 0), (4, 0), (5, 0), (6, 0), (8, 0), (9, 0), (10, 0), (11, 0), (13, 0)]),
+        ..PlayerOptions::default()
     });
 
     map.insert("cliffs", PlayerOptions {
-        tracks: &[(2, 0)],
-        delay_mul: 0.5
+        tracks: Cow::Borrowed(&[(2, 0)]),
+        ..PlayerOptions::default()
     });
 
     map.insert("pacman", PlayerOptions {
-        tracks: &[(1, 0), (2, 0)],
-        delay_mul: 5.0
+        tracks: Cow::Borrowed(&[(1, 0), (2, 0)]),
+        ..PlayerOptions::default()
     });
 
     map.insert("smwintro", PlayerOptions {
-        tracks: &[(1, -1), (2, -1), (3, -1), (4, -1)],
-        delay_mul: 3.0
+        tracks: Cow::Borrowed(&[(1, 0), (2, 0), (3, 0), (4, 0)]),
+        ..PlayerOptions::default()
     });
 
     map.insert("OoTBoF", PlayerOptions {
-        tracks: &[(1, 0), (2, 0), (4, 0), (6, 0)],
-        delay_mul: 2.0
+        tracks: Cow::Borrowed(&[(1, 0), (2, 0), (4, 0), (6, 0)]),
+        ..PlayerOptions::default()
     });
 
     map.insert("SSB_hammer", PlayerOptions {
-        tracks: &[(1, 0), (2, 0)],
-        delay_mul: 1.0
+        tracks: Cow::Borrowed(&[(1, 0), (2, 0)]),
+        ..PlayerOptions::default()
     });
 
     map.insert("Fox_Wins", PlayerOptions {
-        tracks: &[(0, 0)],
-        delay_mul: 5.0
+        tracks: Cow::Borrowed(&[(0, 0)]),
+        ..PlayerOptions::default()
     });
 
     map.insert("HappyBirthday", PlayerOptions {
-        tracks: &[(1, 0), (2, 0), (3, 0)],
-        delay_mul: 3.0
+        tracks: Cow::Borrowed(&[(1, 0), (2, 0), (3, 0)]),
+        ..PlayerOptions::default()
     });
 
     map.insert("OoTSoT", PlayerOptions {
-        tracks: &[(1, 1), (2, 1), (4, 0)],
-        delay_mul: 2.0
+        tracks: Cow::Borrowed(&[(1, 0), (2, 0), (4, 0)]),
+        ..PlayerOptions::default()
     });
 
     map.insert("symph40", PlayerOptions {
-        tracks: &[(1, 0), (2, 0), (3, 0), (4, 0)],
-        delay_mul: 0.5
+        tracks: Cow::Borrowed(&[(1, 0), (2, 0), (3, 0), (4, 0)]),
+        ..PlayerOptions::default()
     });
 
     map.insert("Z64gerud", PlayerOptions {
-        tracks: &[(1, 0), (2, 0), (3, 0), (4, 0), (5, 0), (6, 0), (7, 0)],
-        delay_mul: 4.0
+        tracks: Cow::Borrowed(&[(1, 0), (2, 0), (3, 0), (4, 0), (5, 0), (6, 0), (7, 0)]),
+        ..PlayerOptions::default()
     });
 
     map